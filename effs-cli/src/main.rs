@@ -1,14 +1,22 @@
 use clap::Parser;
 use effs::{
     Effs,
-    effect::Mirror,
+    Refresh,
+    cache::DiskCache,
+    effect::{
+        Mirror,
+        Symlinks,
+    },
     source::Source,
 };
 use fuse3::{
     MountOptions,
     raw::Session,
 };
-use std::path::Path;
+use std::{
+    path::PathBuf,
+    time::Duration,
+};
 use tokio::signal;
 use tracing::Level;
 
@@ -17,6 +25,49 @@ struct Cli {
     mount_path: String,
     #[clap(long)]
     mirror_source: Option<String>,
+    /// When to list the mirrored directories again: `always`, `once`, `explicit` (only when
+    /// changes are watched for), or a number of seconds to reuse the listings for
+    #[clap(long, default_value = "always", value_parser = parse_refresh)]
+    mirror_refresh: Refresh,
+    /// How symbolic links within the mirrored directory are presented: `preserve`, `follow`,
+    /// or `follow-within` to only follow those that point within the mirrored directory
+    #[clap(long, default_value = "preserve", value_parser = parse_symlinks)]
+    mirror_symlinks: Symlinks,
+    /// Maximum bytes of filtrated outputs to keep in memory for reuse once their files are
+    /// closed; the mirrored files are read as they are, so they are never cached; 0 disables
+    /// the cache
+    #[clap(long, default_value_t = 0)]
+    cache_size: usize,
+    /// Directory to persist the outputs of keyed filters in, so they may be reused across
+    /// remounts; the mirrored files are read as they are, so they are never cached
+    #[clap(long)]
+    disk_cache: Option<PathBuf>,
+    /// Maximum bytes of the outputs of keyed filters to keep in the disk cache
+    #[clap(long, default_value_t = 1 << 30)]
+    disk_cache_size: u64,
+    /// Watch the mirrored directory for changes, so they are reflected without remounting
+    #[clap(long)]
+    watch: bool,
+}
+
+fn parse_refresh(value: &str) -> Result<Refresh, String> {
+    match value {
+        "always" => Ok(Refresh::Always),
+        "once" => Ok(Refresh::Once),
+        "explicit" => Ok(Refresh::Explicit),
+        secs => secs.parse()
+            .map(|secs| Refresh::Ttl(Duration::from_secs(secs)))
+            .map_err(|_| format!("expected always, once, explicit or seconds, got {secs:?}")),
+    }
+}
+
+fn parse_symlinks(value: &str) -> Result<Symlinks, String> {
+    match value {
+        "preserve" => Ok(Symlinks::Preserve),
+        "follow" => Ok(Symlinks::Follow),
+        "follow-within" => Ok(Symlinks::FollowWithin),
+        other => Err(format!("expected preserve, follow or follow-within, got {other:?}")),
+    }
 }
 
 fn log_init() {
@@ -39,18 +90,28 @@ async fn main() {
     mount_options
         .uid(uid)
         .gid(gid)
-        .force_readdir_plus(true)
-        // not using the opendir for now, even though we may support this later.
-        .no_open_dir_support(true)
         .read_only(true);
 
-    let effs = Effs::default();
+    let mut builder = Effs::builder()
+        .cache_size(args.cache_size)
+        .watch(args.watch);
+    if let Some(disk_cache) = args.disk_cache {
+        builder = builder.disk_cache(
+            DiskCache::new(disk_cache, args.disk_cache_size)
+                .expect("error with disk cache"),
+        );
+    }
+    let effs = builder.build();
     if let Some(mirror_source) = args.mirror_source {
-        effs.push_source(Source::new(
-            mirror_source.into(),
-            "".into(),
-            Mirror,
-        ))
+        effs.push_source_with_refresh(
+            Source::new(
+                mirror_source.into(),
+                "".into(),
+                Mirror::default()
+                    .with_symlinks(args.mirror_symlinks),
+            ),
+            args.mirror_refresh,
+        )
             .await
             .expect("error with mirror source");
     }
@@ -1 +1,2 @@
 pub mod transform;
+pub mod variant;
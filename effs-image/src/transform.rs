@@ -1,103 +1,892 @@
 use effs::{
-    error::EffectError,
-    entry::Entry,
+    cache::{
+        CacheKey,
+        EffectId,
+    },
+    effect::{
+        Mirror,
+        Symlinks,
+    },
+    error::{
+        EffectError,
+        Error,
+    },
+    entry::{
+        Attr,
+        Entry,
+    },
     filter::Filter,
     future::Filtrate,
-    source::Source,
-    traits::{
-        Effect,
-        EffsSource,
+    traits::AsyncEffect,
+};
+use futures_util::future::BoxFuture;
+use image::{
+    DynamicImage,
+    ImageDecoder as _,
+    ImageEncoder,
+    ImageFormat,
+    ImageReader,
+    ImageResult,
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{
+            self,
+            PngEncoder,
+        },
+        webp::WebPEncoder,
     },
+    imageops::FilterType,
+    metadata::Orientation,
 };
 use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
     ffi::OsString,
-    fs::File,
+    hash::Hash,
     io::{
-        Read,
-        Seek,
-        SeekFrom,
+        self,
+        Cursor,
     },
     path::{
         Path,
         PathBuf,
     },
+    sync::Arc,
 };
+use tokio::task::spawn_blocking;
 
+/// How a rectangle that extends beyond the bounds of the image is handled.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum OutOfBounds {
+    /// The rectangle is reduced to the part that is within the image, though a rectangle that
+    /// is entirely outside of the image is still an error.
+    #[default]
+    Clamp,
+    /// The rectangle must be entirely within the image.
+    Error,
+}
+
+/// Crops the source image to the rectangle at `x, y` that is `w` by `h` pixels, where the
+/// output is encoded in the same format as the source.
+///
+/// The size of the output can only be known once it is produced, so it is left to be determined
+/// by producing the output when it is first requested.
+#[derive(Clone, Copy, Hash)]
 pub struct Crop {
-    x: usize,
-    y: usize,
-    w: usize,
-    h: usize,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    out_of_bounds: OutOfBounds,
 }
 
 impl Crop {
     pub fn new(
-        x: usize,
-        y: usize,
-        w: usize,
-        h: usize,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
     ) -> Self {
-        Self { x, y, w, h }
-    }
-}
-
-impl Effect for Crop {
-    fn apply(&mut self, path: &Path, request: &Path) -> Result<Vec<(OsString, Entry)>, EffectError> {
-        let path = path.to_owned();
-        let basename = path.clone()
-            .file_name()
-            .ok_or_else(|| EffectError::BadSourcePath(path.clone(), "no final component found for source"))?
-            .to_owned();
-        // TODO actually implement image filter; for now use seek/read length as surrogate placeholder
-        let start = self.x as u64;
-        let len = self.w;
-        Ok(vec![
-            (
-                basename,
-                Filter::new(move || {
-                    let path = path.to_owned();
-                    Filtrate::new(
-                        async move {
-                            let mut file = File::open(&path)?;
-                            file.seek(SeekFrom::Start(start))?;
-                            let mut output = vec![0; len];
-                            file.read(&mut output)?;
-                            Ok(output.into())
-                        }
-                    )
-                }).into()
-            )
-        ])
+        Self { x, y, w, h, out_of_bounds: OutOfBounds::default() }
+    }
+
+    pub fn with_out_of_bounds(mut self, out_of_bounds: OutOfBounds) -> Self {
+        self.out_of_bounds = out_of_bounds;
+        self
+    }
+
+    /// The rectangle within an image of the dimensions, as `x, y, w, h`.
+    fn rect(&self, width: u32, height: u32) -> io::Result<(u32, u32, u32, u32)> {
+        let right = self.x.saturating_add(self.w);
+        let bottom = self.y.saturating_add(self.h);
+        if self.w == 0 || self.h == 0 || self.x >= width || self.y >= height {
+            return Err(invalid_input("crop rectangle is outside of the image"));
+        }
+        if self.out_of_bounds == OutOfBounds::Error && (right > width || bottom > height) {
+            return Err(invalid_input("crop rectangle extends beyond the image"));
+        }
+        Ok((self.x, self.y, right.min(width) - self.x, bottom.min(height) - self.y))
+    }
+}
+
+impl EffectId for Crop {
+    const EFFECT_ID: &'static str = "effs-image::Crop/1";
+}
+
+impl AsyncEffect for Crop {
+    fn apply<'a>(
+        &'a mut self,
+        path: &'a Path,
+        _request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        let crop = *self;
+        Box::pin(async move {
+            let basename = path.file_name()
+                .ok_or_else(|| EffectError::BadSourcePath(path.into(), "no final component found for source"))?
+                .to_owned();
+            let attr = tokio::fs::metadata(path)
+                .await
+                .map(|metadata| Attr::inherit(&metadata))
+                .unwrap_or_default();
+            Ok(vec![(basename, image_filter(path.into(), attr, &crop, move |source| {
+                let (image, format) = decode(source)?;
+                let (x, y, w, h) = crop.rect(image.width(), image.height())?;
+                encode(&image.crop_imm(x, y, w, h), format)
+            }).into())])
+        })
+    }
+}
+
+/// How the image is fitted to the target dimensions of a `Resize`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum Fit {
+    /// Exactly the target dimensions, without preserving the aspect ratio.
+    Exact,
+    /// As large as possible while within the target dimensions.
+    #[default]
+    Contain,
+    /// As small as possible while covering the target dimensions, with the excess cropped
+    /// evenly from both sides such that the output is exactly the target dimensions.
+    Cover,
+    /// The target width, with the height following the aspect ratio.
+    Width,
+    /// The target height, with the width following the aspect ratio.
+    Height,
+}
+
+/// The filter used for resampling the image as it is resized.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum Resample {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<Resample> for FilterType {
+    fn from(resample: Resample) -> Self {
+        match resample {
+            Resample::Nearest => FilterType::Nearest,
+            Resample::Triangle => FilterType::Triangle,
+            Resample::CatmullRom => FilterType::CatmullRom,
+            Resample::Gaussian => FilterType::Gaussian,
+            Resample::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Resizes the source image to the target dimensions, where the output is encoded in the same
+/// format as the source.
+///
+/// Should the source be a directory, every image within it is resized, while everything else
+/// is presented as it is.
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct Resize {
+    w: u32,
+    h: u32,
+    fit: Fit,
+    resample: Resample,
+}
+
+impl Resize {
+    pub fn new(w: u32, h: u32) -> Self {
+        Self { w, h, fit: Fit::default(), resample: Resample::default() }
+    }
+
+    pub fn with_fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    pub fn with_resample(mut self, resample: Resample) -> Self {
+        self.resample = resample;
+        self
+    }
+
+    fn resize(&self, image: &DynamicImage) -> io::Result<DynamicImage> {
+        let (w, h) = match self.fit {
+            Fit::Width => (self.w, u32::MAX),
+            Fit::Height => (u32::MAX, self.h),
+            _ => (self.w, self.h),
+        };
+        if w == 0 || h == 0 {
+            return Err(invalid_input("resize to an empty image"));
+        }
+        let filter = self.resample.into();
+        Ok(match self.fit {
+            Fit::Exact => image.resize_exact(w, h, filter),
+            Fit::Cover => image.resize_to_fill(w, h, filter),
+            Fit::Contain | Fit::Width | Fit::Height => image.resize(w, h, filter),
+        })
+    }
+}
+
+impl EffectId for Resize {
+    const EFFECT_ID: &'static str = "effs-image::Resize/1";
+}
+
+impl AsyncEffect for Resize {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        let resize = *self;
+        Box::pin(images(origin, request, move |path, name, attr| {
+            let filter = image_filter(path, attr, &resize, move |source| {
+                let (image, format) = decode(source)?;
+                encode(&resize.resize(&image)?, format)
+            });
+            (name, filter.into())
+        }))
+    }
+}
+
+/// The level of compression for the formats that support more than one.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum Compression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl From<Compression> for png::CompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Fast => png::CompressionType::Fast,
+            Compression::Default => png::CompressionType::Default,
+            Compression::Best => png::CompressionType::Best,
+        }
+    }
+}
+
+/// Converts the source image to another format, with the extension of the name replaced with
+/// the one for the format, e.g. `photo.png` becomes `photo.jpg`.
+///
+/// Should the source be a directory, every image within it is converted, while everything
+/// else is presented as it is.  When converted names collide, such as both `a.png` and `a.tif`
+/// becoming `a.jpg`, the name is kept by an image that was already in the format, otherwise
+/// by the first one by name; the others have their original extension as a suffix, such as
+/// `a~tif.jpg`, in the manner of `Conflict::Suffix`.
+///
+/// WebP is only ever encoded losslessly, as that is all the encoder supports, such that the
+/// quality has no effect on it.
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct Convert {
+    format: ImageFormat,
+    quality: u8,
+    compression: Compression,
+}
+
+impl Convert {
+    pub fn new(format: ImageFormat) -> Self {
+        Self { format, quality: 90, compression: Compression::default() }
+    }
+
+    /// The quality from 1 to 100, for the lossy formats, which is only JPEG, as WebP is always
+    /// encoded losslessly.
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn encode(&self, image: &DynamicImage) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let result = match self.format {
+            // neither supports every color type, so convert to one that is supported
+            ImageFormat::Jpeg => {
+                let image = if image.color().has_color() {
+                    DynamicImage::ImageRgb8(image.to_rgb8())
+                } else {
+                    DynamicImage::ImageLuma8(image.to_luma8())
+                };
+                image.write_with_encoder(JpegEncoder::new_with_quality(&mut output, self.quality))
+            }
+            ImageFormat::WebP => {
+                let image = if image.color().has_alpha() {
+                    DynamicImage::ImageRgba8(image.to_rgba8())
+                } else {
+                    DynamicImage::ImageRgb8(image.to_rgb8())
+                };
+                // the encoder is lossless only, so the quality does not apply
+                image.write_with_encoder(WebPEncoder::new_lossless(&mut output))
+            }
+            ImageFormat::Png => image.write_with_encoder(PngEncoder::new_with_quality(
+                &mut output,
+                self.compression.into(),
+                png::FilterType::Adaptive,
+            )),
+            format => return encode(image, format),
+        };
+        result.map_err(invalid_data)?;
+        Ok(output)
+    }
+
+    fn extension(&self) -> &'static str {
+        self.format
+            .extensions_str()
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The name of the image once converted.
+    fn name(&self, name: &OsString) -> OsString {
+        Path::new(name)
+            .with_extension(self.extension())
+            .into_os_string()
+    }
+
+    /// The name of the image once converted, with the original extension as a suffix.
+    fn suffixed(&self, name: &OsString) -> OsString {
+        let path = Path::new(name);
+        let mut result = path.file_stem()
+            .unwrap_or(name)
+            .to_os_string();
+        if let Some(extension) = path.extension() {
+            result.push("~");
+            result.push(extension);
+        }
+        result.push(".");
+        result.push(self.extension());
+        result
+    }
+
+    /// Whether the image at the name is already in the format.
+    fn is_format(&self, name: &OsString) -> bool {
+        ImageFormat::from_path(name).is_ok_and(|format| format == self.format)
+    }
+}
+
+impl EffectId for Convert {
+    const EFFECT_ID: &'static str = "effs-image::Convert/1";
+}
+
+impl AsyncEffect for Convert {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        let convert = *self;
+        Box::pin(async move {
+            // the original names of the converted images, which are only renamed once all the
+            // names are known
+            let mut converted = HashSet::new();
+            let listing = images(origin, request, |path, name, attr| {
+                let filter = image_filter(path, attr, &convert, move |source| {
+                    let (image, _) = decode(source)?;
+                    convert.encode(&image)
+                });
+                converted.insert(name.clone());
+                (name, filter.into())
+            }).await?;
+
+            // the images that were already in the format are given their names first
+            let mut order = listing.iter()
+                .map(|(name, _)| name)
+                .filter(|name| converted.contains(*name))
+                .collect::<Vec<_>>();
+            order.sort_by_key(|name| (!convert.is_format(name), *name));
+            let mut taken = listing.iter()
+                .map(|(name, _)| name)
+                .filter(|name| !converted.contains(*name))
+                .cloned()
+                .collect::<HashSet<_>>();
+            let mut renamed = HashMap::new();
+            for name in order {
+                let mut target = convert.name(name);
+                if taken.contains(&target) {
+                    target = convert.suffixed(name);
+                }
+                taken.insert(target.clone());
+                renamed.insert(name.clone(), target);
+            }
+            Ok(listing.into_iter()
+                .map(|(name, entry)| (renamed.remove(&name).unwrap_or(name), entry))
+                .collect())
+        })
+    }
+}
+
+/// Rotates and flips the pixels of the source image, such that it will appear upright without
+/// the orientation in its EXIF metadata being honored, with the output encoded in the same
+/// format as the source.
+///
+/// The orientation from the EXIF metadata is applied first, followed by the explicit
+/// orientation, if any.  Whenever the pixels are transformed, the tag is reset in the output for
+/// the formats where the metadata is retained, and dropped along with the rest of the metadata
+/// otherwise, as the tag would no longer describe the pixels.
+///
+/// Should the source be a directory, every image within it is oriented, while everything else
+/// is presented as it is.
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct Orient {
+    exif: bool,
+    orientation: Orientation,
+}
+
+impl Default for Orient {
+    fn default() -> Self {
+        Self { exif: true, orientation: Orientation::NoTransforms }
+    }
+}
+
+impl Orient {
+    /// Whether the orientation from the EXIF metadata is applied.
+    pub fn with_exif(mut self, exif: bool) -> Self {
+        self.exif = exif;
+        self
+    }
+
+    /// The rotation and flip to apply.
+    pub fn with_orientation(mut self, orientation: Orientation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    fn orient(&self, source: Vec<u8>) -> io::Result<Vec<u8>> {
+        let reader = ImageReader::new(Cursor::new(source))
+            .with_guessed_format()?;
+        let format = reader.format()
+            .ok_or_else(|| invalid_input("unrecognized image format"))?;
+        let mut decoder = reader.into_decoder()
+            .map_err(invalid_data)?;
+        let mut exif = decoder.exif_metadata()
+            .map_err(invalid_data)?;
+        let orientation = decoder.orientation()
+            .map_err(invalid_data)?;
+        let mut image = DynamicImage::from_decoder(decoder)
+            .map_err(invalid_data)?;
+        if self.exif {
+            image.apply_orientation(orientation);
+        }
+        image.apply_orientation(self.orientation);
+        if self.exif || self.orientation != Orientation::NoTransforms {
+            if let Some(exif) = &mut exif {
+                let _ = Orientation::remove_from_exif_chunk(exif);
+            }
+        }
+
+        let mut output = Vec::new();
+        let result = match format {
+            ImageFormat::Jpeg => with_exif(&image, JpegEncoder::new(&mut output), exif),
+            ImageFormat::Png => with_exif(&image, PngEncoder::new(&mut output), exif),
+            ImageFormat::WebP => with_exif(&image, WebPEncoder::new_lossless(&mut output), exif),
+            format => return encode(&image, format),
+        };
+        result.map_err(invalid_data)?;
+        Ok(output)
     }
 }
 
+impl EffectId for Orient {
+    const EFFECT_ID: &'static str = "effs-image::Orient/1";
+}
+
+impl AsyncEffect for Orient {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        let orient = *self;
+        Box::pin(images(origin, request, move |path, name, attr| {
+            (name, image_filter(path, attr, &orient, move |source| orient.orient(source)).into())
+        }))
+    }
+}
+
+/// Encode the image along with the EXIF metadata.
+fn with_exif(
+    image: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    exif: Option<Vec<u8>>,
+) -> ImageResult<()> {
+    if let Some(exif) = exif {
+        encoder.set_exif_metadata(exif)
+            .map_err(image::ImageError::Unsupported)?;
+    }
+    image.write_with_encoder(encoder)
+}
+
+/// Present the source through `image` should it be an image, or should it be a directory,
+/// present every image within through `image` and everything else as it is.  The function is
+/// given the path to the image, the name it is listed as, and the timestamps of the image, as
+/// the output derived from it is modified whenever the image is.
+///
+/// Only the symbolic links that point to somewhere within the origin are followed, such that
+/// the images outside of what the source is meant to present are never read.
+pub(crate) async fn images(
+    origin: &Path,
+    request: &Path,
+    mut image: impl FnMut(PathBuf, OsString, Attr) -> (OsString, Entry),
+) -> Result<Vec<(OsString, Entry)>, EffectError> {
+    if let Ok(metadata) = tokio::fs::metadata(origin).await {
+        if metadata.is_file() {
+            if request != Path::new("") {
+                return Err(EffectError::BadRequestPath(request.into(), "not a directory"))
+            }
+            let name = origin.file_name()
+                .ok_or_else(|| EffectError::BadSourcePath(origin.into(), "no final component found for source"))?
+                .to_owned();
+            return Ok(vec![image(origin.into(), name, Attr::inherit(&metadata))]);
+        }
+    }
+    let listing = Mirror::default()
+        .with_symlinks(Symlinks::FollowWithin)
+        .apply(origin, request)
+        .await?;
+    let path = origin.join(request);
+    Ok(listing.into_iter()
+        .map(|(name, entry)| match entry {
+            Entry::Dir(_) | Entry::LazyDir(_) => (name, entry),
+            _ if is_image(&name) => {
+                let attr = entry.attr()
+                    .map(|attr| Attr {
+                        atime: attr.atime,
+                        mtime: attr.mtime,
+                        ctime: attr.ctime,
+                        ..Default::default()
+                    })
+                    .unwrap_or_default();
+                image(path.join(&name), name, attr)
+            }
+            entry => (name, entry),
+        })
+        .collect())
+}
+
+/// Whether the name has the extension of an image format that can be decoded.
+fn is_image(name: &OsString) -> bool {
+    ImageFormat::from_path(name).is_ok_and(|format| format.reading_enabled())
+}
+
+/// A filter that produces the output of `transform` on the contents of the source image at the
+/// path, keyed by the effect that is applying the transform, presented with the attributes.
+pub(crate) fn image_filter<E: EffectId>(
+    path: PathBuf,
+    attr: Attr,
+    effect: &E,
+    transform: impl Fn(Vec<u8>) -> io::Result<Vec<u8>> + Send + Sync + 'static,
+) -> Filter {
+    let key = CacheKey::new(&path, effect);
+    let transform = Arc::new(transform);
+    Filter::new(move || {
+        let path = path.clone();
+        let transform = transform.clone();
+        Filtrate::new(
+            async move {
+                let source = tokio::fs::read(&path).await?;
+                let output = spawn_blocking(move || transform(source))
+                    .await
+                    .map_err(|_| Error::Internal)??;
+                Ok(output.into())
+            }
+        )
+    })
+        .with_key(key)
+        .with_attr(attr)
+}
+
+pub(crate) fn invalid_input(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+pub(crate) fn invalid_data(e: image::ImageError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Decode the image, along with the format it was encoded in.
+pub(crate) fn decode(source: Vec<u8>) -> io::Result<(DynamicImage, ImageFormat)> {
+    let reader = ImageReader::new(Cursor::new(source))
+        .with_guessed_format()?;
+    let format = reader.format()
+        .ok_or_else(|| invalid_input("unrecognized image format"))?;
+    Ok((reader.decode().map_err(invalid_data)?, format))
+}
+
+pub(crate) fn encode(image: &DynamicImage, format: ImageFormat) -> io::Result<Vec<u8>> {
+    let mut output = Cursor::new(Vec::new());
+    image.write_to(&mut output, format)
+        .map_err(invalid_data)?;
+    Ok(output.into_inner())
+}
+
 #[cfg(test)]
 mod test {
-    use std::io::Write;
+    use effs::{
+        Effs,
+        source::Source,
+        traits::AsyncEffsSource,
+    };
+    use image::{
+        Rgb,
+        RgbImage,
+    };
+    use std::path::PathBuf;
     use tempfile::tempdir;
 
     use super::*;
 
+    /// A 4 by 3 image where every pixel is distinct.
+    fn gradient() -> DynamicImage {
+        RgbImage::from_fn(4, 3, |x, y| Rgb([x as u8 * 10, y as u8 * 10, 0])).into()
+    }
+
+    async fn filtrate(source: &Path, crop: Crop) -> Result<Vec<u8>, effs::error::Error> {
+        let mut effs_source = Source::new(source.into(), "".into(), crop);
+        let result = effs_source.dir(Path::new("")).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].0, PathBuf::from(source.file_name().expect("has file name")));
+        match &result[0].1 {
+            Entry::Filter(filter) => Ok(filter.filtrate().await?.to_vec()),
+            _ => unreachable!(),
+        }
+    }
+
     #[tokio::test]
     async fn crop() -> anyhow::Result<()> {
         let root = tempdir()?;
-        let source = root.path().join("source");
-        let mut source_file = File::create(source.clone())?;
-        writeln!(source_file, "0123456789")?;
-
-        let mut effs_source = Source::new(
-            source,
-            "".into(),
-            Crop::new(1, 1, 4, 4),
+        let source = root.path().join("source.png");
+        gradient().save(&source)?;
+
+        let output = filtrate(&source, Crop::new(1, 1, 2, 2)).await?;
+        assert_eq!(image::guess_format(&output)?, ImageFormat::Png);
+        let cropped = image::load_from_memory(&output)?.to_rgb8();
+        assert_eq!(cropped.dimensions(), (2, 2));
+        assert_eq!(cropped.get_pixel(0, 0), &Rgb([10, 10, 0]));
+        assert_eq!(cropped.get_pixel(1, 1), &Rgb([20, 20, 0]));
+
+        // the format of the source is retained
+        let source = root.path().join("source.jpg");
+        gradient().save(&source)?;
+        let output = filtrate(&source, Crop::new(0, 0, 2, 2)).await?;
+        assert_eq!(image::guess_format(&output)?, ImageFormat::Jpeg);
+        assert_eq!(image::load_from_memory(&output)?.width(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn out_of_bounds() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let source = root.path().join("source.png");
+        gradient().save(&source)?;
+
+        let output = filtrate(&source, Crop::new(2, 1, 10, 10)).await?;
+        let cropped = image::load_from_memory(&output)?;
+        assert_eq!((cropped.width(), cropped.height()), (2, 2));
+
+        let strict = Crop::new(2, 1, 10, 10).with_out_of_bounds(OutOfBounds::Error);
+        assert!(filtrate(&source, strict).await.is_err());
+        assert!(filtrate(&source, Crop::new(4, 0, 1, 1)).await.is_err());
+        assert!(filtrate(&source, Crop::new(0, 0, 0, 1)).await.is_err());
+
+        // not an image
+        let source = root.path().join("source.txt");
+        std::fs::write(&source, b"0123456789")?;
+        assert!(filtrate(&source, Crop::new(0, 0, 1, 1)).await.is_err());
+        Ok(())
+    }
+
+    async fn dimensions(entry: &Entry) -> anyhow::Result<(u32, u32)> {
+        let output = match entry {
+            Entry::Filter(filter) => filter.filtrate().await?,
+            _ => unreachable!(),
+        };
+        let image = image::load_from_memory(&output)?;
+        Ok((image.width(), image.height()))
+    }
+
+    #[tokio::test]
+    async fn resize() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let source = root.path().join("source.png");
+        RgbImage::new(8, 4).save(&source)?;
+
+        for (fit, expected) in [
+            (Fit::Exact, (4, 4)),
+            (Fit::Contain, (4, 2)),
+            (Fit::Cover, (4, 4)),
+            (Fit::Width, (4, 2)),
+            (Fit::Height, (8, 4)),
+        ] {
+            let resize = Resize::new(4, 4)
+                .with_fit(fit)
+                .with_resample(Resample::Triangle);
+            let mut effs_source = Source::new(source.clone(), "".into(), resize);
+            let result = effs_source.dir(Path::new("")).await?;
+            assert_eq!(result.len(), 1);
+            assert_eq!(dimensions(&result[0].1).await?, expected, "{fit:?}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resize_dir() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::create_dir(root.path().join("sub"))?;
+        RgbImage::new(8, 4).save(root.path().join("a.png"))?;
+        RgbImage::new(2, 8).save(root.path().join("sub").join("b.jpg"))?;
+        std::fs::write(root.path().join("notes.txt"), b"notes")?;
+        // only the links to within the origin are followed
+        let outside = tempdir()?;
+        RgbImage::new(8, 4).save(outside.path().join("outside.png"))?;
+        std::os::unix::fs::symlink(outside.path().join("outside.png"), root.path().join("outside.png"))?;
+        std::os::unix::fs::symlink(root.path().join("a.png"), root.path().join("link.png"))?;
+
+        let mut effs_source = Source::new(root.path().into(), "".into(), Resize::new(4, 4));
+        let mut result = effs_source.dir(Path::new("")).await?;
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            result.iter().map(|(name, _)| name.as_os_str()).collect::<Vec<_>>(),
+            ["a.png", "link.png", "notes.txt", "sub"],
         );
-        let result = effs_source.dir(Path::new(""))?;
+        assert_eq!(dimensions(&result[0].1).await?, (4, 2));
+        assert_eq!(dimensions(&result[1].1).await?, (4, 2));
+        // the output is modified whenever the image is
+        let mtime = std::fs::metadata(root.path().join("a.png"))?.modified()?;
+        assert_eq!(result[0].1.attr().and_then(|attr| attr.mtime), Some(mtime));
+        // anything that is not an image is presented as it is
+        match &result[2].1 {
+            Entry::PreciseFilter(filter) => assert_eq!(filter.filtrate(0, 16).await?, b"notes".as_ref()),
+            _ => unreachable!(),
+        }
+        assert!(matches!(result[3].1, Entry::Dir(_)));
+
+        let result = effs_source.dir(Path::new("sub")).await?;
         assert_eq!(result.len(), 1);
-        assert_eq!(result[0].0, PathBuf::from("source"));
-        let filtrate = match &result[0].1 {
+        assert_eq!(dimensions(&result[0].1).await?, (1, 4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn convert() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        for name in ["a.png", "a.tif", "b.png", "b.jpg", "c.v2.png"] {
+            gradient().save(root.path().join(name))?;
+        }
+        gradient().save(root.path().join("d.png"))?;
+        std::fs::create_dir(root.path().join("d.jpg"))?;
+        std::fs::write(root.path().join("notes.txt"), b"notes")?;
+
+        let convert = Convert::new(ImageFormat::Jpeg).with_quality(80);
+        let mut effs_source = Source::new(root.path().into(), "".into(), convert);
+        let mut result = effs_source.dir(Path::new("")).await?;
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            result.iter().map(|(name, _)| name.as_os_str()).collect::<Vec<_>>(),
+            ["a.jpg", "a~tif.jpg", "b.jpg", "b~png.jpg", "c.v2.jpg", "d.jpg", "d~png.jpg", "notes.txt"],
+        );
+        for (name, entry) in &result {
+            if let Entry::Filter(filter) = entry {
+                let output = filter.filtrate().await?;
+                assert_eq!(image::guess_format(&output)?, ImageFormat::Jpeg, "{name:?}");
+            }
+        }
+        // what is not an image keeps its name, even with the extension of the format
+        assert!(matches!(result[5].1, Entry::Dir(_)));
+
+        // a lower quality produces a smaller output
+        let source = root.path().join("noise.png");
+        RgbImage::from_fn(64, 64, |x, y| Rgb([((x * 31) ^ (y * 17)) as u8, (x * y) as u8, (x + y * 7) as u8]))
+            .save(&source)?;
+        let mut sizes = Vec::new();
+        for quality in [10, 95] {
+            let convert = Convert::new(ImageFormat::Jpeg).with_quality(quality);
+            let result = Source::new(source.clone(), "".into(), convert).dir(Path::new("")).await?;
+            assert_eq!(result[0].0, "noise.jpg");
+            match &result[0].1 {
+                Entry::Filter(filter) => sizes.push(filter.filtrate().await?.len()),
+                _ => unreachable!(),
+            }
+        }
+        assert!(sizes[0] < sizes[1]);
+
+        // the alpha channel is retained where the format supports it
+        let source = root.path().join("alpha.png");
+        DynamicImage::ImageRgba8(image::RgbaImage::new(2, 2)).save(&source)?;
+        let convert = Convert::new(ImageFormat::WebP);
+        let result = Source::new(source, "".into(), convert).dir(Path::new("")).await?;
+        assert_eq!(result[0].0, "alpha.webp");
+        let output = match &result[0].1 {
             Entry::Filter(filter) => filter.filtrate().await?,
             _ => unreachable!(),
         };
-        assert_eq!(filtrate, b"1234".to_vec());
+        assert!(image::load_from_memory_with_format(&output, ImageFormat::WebP)?.color().has_alpha());
+        Ok(())
+    }
+
+    /// An EXIF chunk with only the orientation tag.
+    fn exif(orientation: Orientation) -> Vec<u8> {
+        let mut chunk = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0".to_vec();
+        chunk.extend([orientation.to_exif(), 0, 0, 0, 0, 0, 0]);
+        chunk
+    }
+
+    fn orientation(output: &[u8]) -> anyhow::Result<Option<Orientation>> {
+        let mut decoder = ImageReader::new(Cursor::new(output))
+            .with_guessed_format()?
+            .into_decoder()?;
+        Ok(decoder.exif_metadata()?
+            .and_then(|exif| Orientation::from_exif_chunk(&exif)))
+    }
+
+    #[tokio::test]
+    async fn orient() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let source = root.path().join("source.png");
+        let image = RgbImage::from_fn(4, 2, |x, y| if (x, y) == (0, 0) { Rgb([255, 0, 0]) } else { Rgb([0, 0, 0]) });
+        let mut encoder = PngEncoder::new(std::fs::File::create(&source)?);
+        encoder.set_exif_metadata(exif(Orientation::Rotate90))?;
+        DynamicImage::ImageRgb8(image).write_with_encoder(encoder)?;
+
+        for (orient, dimensions, red, tag) in [
+            (Orient::default(), (2, 4), (1, 0), Orientation::NoTransforms),
+            (Orient::default().with_orientation(Orientation::FlipVertical), (2, 4), (1, 3), Orientation::NoTransforms),
+            (Orient::default().with_exif(false).with_orientation(Orientation::Rotate180), (4, 2), (3, 1), Orientation::NoTransforms),
+            (Orient::default().with_exif(false), (4, 2), (0, 0), Orientation::Rotate90),
+        ] {
+            let result = Source::new(source.clone(), "".into(), orient).dir(Path::new("")).await?;
+            assert_eq!(result[0].0, "source.png");
+            let output = match &result[0].1 {
+                Entry::Filter(filter) => filter.filtrate().await?,
+                _ => unreachable!(),
+            };
+            let image = image::load_from_memory(&output)?.to_rgb8();
+            assert_eq!(image.dimensions(), dimensions, "{orient:?}");
+            assert_eq!(image.get_pixel(red.0, red.1), &Rgb([255, 0, 0]), "{orient:?}");
+            assert_eq!(orientation(&output)?, Some(tag), "{orient:?}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn size() -> anyhow::Result<()> {
+        use fuse3::raw::{
+            Filesystem,
+            Request,
+        };
+
+        let root = tempdir()?;
+        let source = root.path().join("source.png");
+        gradient().save(&source)?;
+
+        let output = filtrate(&source, Crop::new(1, 1, 2, 2)).await?;
+        let effs = Effs::default();
+        effs.push_source(Source::new(source, "".into(), Crop::new(1, 1, 2, 2))).await?;
+        let entry = effs.lookup(Request::default(), 1, "source.png".as_ref()).await?;
+        assert_eq!(entry.attr.size, output.len() as u64);
         Ok(())
     }
 }
@@ -0,0 +1,307 @@
+use bytes::Bytes;
+use effs::{
+    cache::EffectId,
+    entry::{
+        Attr,
+        Entry,
+        LazyDir,
+    },
+    error::{
+        EffectError,
+        Error,
+    },
+    filter::Filter,
+    future::{
+        FileSize,
+        Filtrate,
+        Listing,
+    },
+    traits::AsyncEffect,
+};
+use futures_util::future::BoxFuture;
+use image::{
+    ImageFormat,
+    ImageReader,
+    imageops::FilterType,
+};
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+};
+use tokio::task::spawn_blocking;
+
+use crate::transform::{
+    Resample,
+    decode,
+    encode,
+    image_filter,
+    images,
+    invalid_data,
+};
+
+/// Presents the source image as a directory of variants of it, such that `photo.jpg/` will
+/// contain `64.jpg`, `256.jpg`, `1024.jpg` for the sizes, along with `original.jpg` and a
+/// `meta.json` that describes the dimensions of all of them.
+///
+/// Every size is the length of the longest edge of the variant, though an image is never
+/// enlarged, such that a variant for a size larger than the image is the same as the original.
+/// The directory is only read once it is accessed, and every variant only once it is read.
+///
+/// Should the source be a directory, every image within it is presented as such a directory,
+/// while everything else is presented as it is.
+#[derive(Clone, Debug, Hash)]
+pub struct Variants {
+    sizes: Arc<[u32]>,
+    resample: Resample,
+}
+
+impl Default for Variants {
+    fn default() -> Self {
+        Self {
+            sizes: Arc::new([64, 256, 1024]),
+            resample: Resample::default(),
+        }
+    }
+}
+
+impl Variants {
+    pub fn with_sizes(mut self, sizes: impl IntoIterator<Item = u32>) -> Self {
+        let mut sizes = sizes.into_iter()
+            .filter(|size| *size > 0)
+            .collect::<Vec<_>>();
+        sizes.sort_unstable();
+        sizes.dedup();
+        self.sizes = sizes.into();
+        self
+    }
+
+    pub fn with_resample(mut self, resample: Resample) -> Self {
+        self.resample = resample;
+        self
+    }
+
+    /// The directory of the variants of the image at the path, along with the timestamps of the
+    /// image that all of them are presented with.
+    fn dir(&self, path: PathBuf, attr: Attr) -> LazyDir {
+        let variants = self.clone();
+        let dir_attr = attr.clone();
+        LazyDir::new(move || {
+            let variants = variants.clone();
+            let path = path.clone();
+            let attr = attr.clone();
+            Listing::new(async move {
+                let dimensions = {
+                    let path = path.clone();
+                    spawn_blocking(move || dimensions(&path))
+                        .await
+                        .map_err(|_| Error::Internal)??
+                };
+                Ok(variants.listing(path, attr, dimensions))
+            })
+        })
+            .with_attr(dir_attr)
+    }
+
+    /// The entries within the directory of the variants of the image with the dimensions.
+    fn listing(
+        &self,
+        path: PathBuf,
+        attr: Attr,
+        (width, height, format): (u32, u32, ImageFormat),
+    ) -> Vec<(OsString, Entry)> {
+        let extension = path.extension()
+            .map(|extension| extension.to_string_lossy().into_owned())
+            .unwrap_or_else(|| format.extensions_str()[0].to_string());
+
+        let mut result = Vec::new();
+        let mut meta = Vec::new();
+        for size in self.sizes.iter() {
+            let name = format!("{size}.{extension}");
+            let variant = Variant::new(width, height, *size, self.resample);
+            meta.push((name.clone(), variant.width, variant.height));
+            let filter = variant.filter(path.clone(), attr.clone(), (width, height), format);
+            result.push((name.into(), filter.into()));
+        }
+        let name = format!("original.{extension}");
+        meta.push((name.clone(), width, height));
+        result.push((name.into(), original(path, attr).into()));
+        result.push(("meta.json".into(), Bytes::from(meta_json(width, height, &meta)).into()));
+        result
+    }
+}
+
+impl AsyncEffect for Variants {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        Box::pin(images(origin, request, |path, name, attr| (name, self.dir(path, attr).into())))
+    }
+}
+
+/// The dimensions of a variant of an image, which also identifies the output for the cache.
+#[derive(Hash)]
+struct Variant {
+    width: u32,
+    height: u32,
+    resample: Resample,
+}
+
+impl EffectId for Variant {
+    const EFFECT_ID: &'static str = "effs-image::Variant/1";
+}
+
+impl Variant {
+    fn new(width: u32, height: u32, size: u32, resample: Resample) -> Self {
+        let longest = width.max(height);
+        if longest <= size {
+            return Self { width, height, resample };
+        }
+        let scale = |n: u32| {
+            ((n as u64 * size as u64 + longest as u64 / 2) / longest as u64).max(1) as u32
+        };
+        Self { width: scale(width), height: scale(height), resample }
+    }
+
+    fn filter(self, path: PathBuf, attr: Attr, original: (u32, u32), format: ImageFormat) -> Filter {
+        image_filter(path, attr, &self, move |source| {
+            if (self.width, self.height) == original {
+                return Ok(source);
+            }
+            let (image, _) = decode(source)?;
+            let filter: FilterType = self.resample.into();
+            encode(&image.resize_exact(self.width, self.height, filter), format)
+        })
+    }
+}
+
+/// The dimensions and format of the image at the path, read without decoding the image.
+fn dimensions(path: &Path) -> io::Result<(u32, u32, ImageFormat)> {
+    let reader = ImageReader::open(path)?
+        .with_guessed_format()?;
+    let format = reader.format()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unrecognized image format"))?;
+    let (width, height) = reader.into_dimensions()
+        .map_err(invalid_data)?;
+    Ok((width, height, format))
+}
+
+/// The image at the path as it is.
+fn original(path: PathBuf, attr: Attr) -> Filter {
+    let size_path = path.clone();
+    Filter::new(move || {
+        let path = path.clone();
+        Filtrate::new(async move { Ok(tokio::fs::read(&path).await?.into()) })
+    })
+        .with_size(move || {
+            let path = size_path.clone();
+            FileSize::new(async move { Ok(tokio::fs::metadata(&path).await?.len()) })
+        })
+        .with_attr(attr)
+}
+
+fn meta_json(width: u32, height: u32, variants: &[(String, u32, u32)]) -> String {
+    let mut result = format!("{{\"width\":{width},\"height\":{height},\"variants\":[");
+    for (i, (name, width, height)) in variants.iter().enumerate() {
+        if i > 0 {
+            result.push(',');
+        }
+        write!(result, "{{\"name\":{},\"width\":{width},\"height\":{height}}}", json_string(name))
+            .expect("writing to a string cannot fail");
+    }
+    result.push_str("]}\n");
+    result
+}
+
+fn json_string(value: &str) -> String {
+    let mut result = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if c.is_control() => {
+                write!(result, "\\u{:04x}", c as u32).expect("writing to a string cannot fail");
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use effs::{
+        Effs,
+        source::Source,
+    };
+    use fuse3::raw::{
+        Filesystem,
+        Request,
+    };
+    use image::RgbImage;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    async fn read(effs: &Effs, parent: u64, name: &str) -> anyhow::Result<(u64, Vec<u8>)> {
+        let entry = effs.lookup(Request::default(), parent, name.as_ref()).await?;
+        let opened = effs.open(Request::default(), entry.attr.ino, 0).await?;
+        let data = effs.read(Request::default(), entry.attr.ino, opened.fh, 0, entry.attr.size as u32).await?;
+        effs.release(Request::default(), entry.attr.ino, opened.fh, 0, 0, false).await?;
+        Ok((entry.attr.size, data.data.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn variants() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        RgbImage::new(400, 200).save(root.path().join("photo.png"))?;
+        std::fs::write(root.path().join("notes.txt"), b"notes")?;
+
+        let effs = Effs::default();
+        let variants = Variants::default().with_sizes([256, 64, 1024]);
+        effs.push_source(Source::new(root.path().into(), "".into(), variants)).await?;
+
+        let photo = effs.lookup(Request::default(), 1, "photo.png".as_ref()).await?;
+        assert_eq!(photo.attr.kind, fuse3::FileType::Directory);
+        let (_, notes) = read(&effs, 1, "notes.txt").await?;
+        assert_eq!(notes, b"notes");
+
+        for (name, expected) in [("64.png", (64, 32)), ("256.png", (256, 128)), ("1024.png", (400, 200))] {
+            let (size, output) = read(&effs, photo.attr.ino, name).await?;
+            assert_eq!(size, output.len() as u64);
+            let image = image::load_from_memory_with_format(&output, ImageFormat::Png)?;
+            assert_eq!((image.width(), image.height()), expected, "{name}");
+        }
+        let (_, original) = read(&effs, photo.attr.ino, "original.png").await?;
+        assert_eq!(original, std::fs::read(root.path().join("photo.png"))?);
+
+        let (_, meta) = read(&effs, photo.attr.ino, "meta.json").await?;
+        assert_eq!(String::from_utf8(meta)?, concat!(
+            r#"{"width":400,"height":200,"variants":["#,
+            r#"{"name":"64.png","width":64,"height":32},"#,
+            r#"{"name":"256.png","width":256,"height":128},"#,
+            r#"{"name":"1024.png","width":400,"height":200},"#,
+            r#"{"name":"original.png","width":400,"height":200}"#,
+            "]}\n",
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn variant() {
+        let variant = Variant::new(3000, 2000, 64, Resample::default());
+        assert_eq!((variant.width, variant.height), (64, 43));
+        let variant = Variant::new(1, 2000, 64, Resample::default());
+        assert_eq!((variant.width, variant.height), (1, 64));
+        let variant = Variant::new(30, 20, 64, Resample::default());
+        assert_eq!((variant.width, variant.height), (30, 20));
+    }
+}
@@ -0,0 +1,138 @@
+use bytes::Bytes;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+mod disk;
+
+pub use disk::{
+    CacheKey,
+    DiskCache,
+    EffectId,
+};
+
+/// A memory bounded cache of completed filtrates, where the least recently used outputs are
+/// evicted once the total size of the cached outputs exceeds the capacity.
+///
+/// Outputs are keyed by the inode and the generation of the node they are produced for, such
+/// that a node being relinked will never be served the output of its previous entry.
+pub(crate) struct FiltrateCache {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<u64, CacheEntry>,
+    // maps from the tick an inode was last used to the inode, oldest first
+    recency: BTreeMap<u64, u64>,
+}
+
+struct CacheEntry {
+    generation: u64,
+    tick: u64,
+    filtrate: Bytes,
+}
+
+impl FiltrateCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub(crate) fn get(&mut self, inode: u64, generation: u64) -> Option<Bytes> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(&inode)?;
+        if entry.generation != generation {
+            return None;
+        }
+        self.recency.remove(&entry.tick);
+        self.recency.insert(tick, inode);
+        entry.tick = tick;
+        Some(entry.filtrate.clone())
+    }
+
+    pub(crate) fn insert(&mut self, inode: u64, generation: u64, filtrate: Bytes) {
+        if self.get(inode, generation).is_some() {
+            return;
+        }
+        // an entry for an older generation may be present
+        self.invalidate(inode);
+        if filtrate.len() > self.capacity {
+            return;
+        }
+        while self.used + filtrate.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.used -= entry.filtrate.len();
+            }
+        }
+        let tick = self.next_tick();
+        self.used += filtrate.len();
+        self.recency.insert(tick, inode);
+        self.entries.insert(inode, CacheEntry { generation, tick, filtrate });
+    }
+
+    pub(crate) fn invalidate(&mut self, inode: u64) {
+        if let Some(entry) = self.entries.remove(&inode) {
+            self.recency.remove(&entry.tick);
+            self.used -= entry.filtrate.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut cache = FiltrateCache::new(10);
+        cache.insert(2, 1, Bytes::from_static(b"1234"));
+        cache.insert(3, 1, Bytes::from_static(b"5678"));
+        assert_eq!(cache.get(2, 1), Some(Bytes::from_static(b"1234")));
+        assert_eq!(cache.get(2, 2), None);
+
+        // inode 3 is the least recently used
+        cache.insert(4, 1, Bytes::from_static(b"90"));
+        cache.insert(5, 1, Bytes::from_static(b"ab"));
+        assert_eq!(cache.used, 8);
+        assert_eq!(cache.get(3, 1), None);
+        assert!(cache.get(2, 1).is_some());
+        assert!(cache.get(4, 1).is_some());
+        assert!(cache.get(5, 1).is_some());
+
+        // larger than the capacity will not be cached
+        cache.insert(6, 1, Bytes::from_static(b"0123456789a"));
+        assert_eq!(cache.get(6, 1), None);
+        assert_eq!(cache.used, 8);
+
+        // a newer generation replaces the older one
+        cache.insert(2, 2, Bytes::from_static(b"cd"));
+        assert_eq!(cache.get(2, 1), None);
+        assert_eq!(cache.get(2, 2), Some(Bytes::from_static(b"cd")));
+        assert_eq!(cache.used, 6);
+
+        cache.invalidate(2);
+        assert_eq!(cache.get(2, 2), None);
+        assert_eq!(cache.used, 4);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = FiltrateCache::new(0);
+        cache.insert(2, 1, Bytes::from_static(b"1234"));
+        assert_eq!(cache.get(2, 1), None);
+        assert_eq!(cache.used, 0);
+    }
+}
@@ -0,0 +1,405 @@
+use bytes::Bytes;
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io::{
+        self,
+        Write as _,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    error::Error,
+    filter::Filter,
+    future::Filtrate,
+};
+
+const TMP_PREFIX: &str = ".tmp-";
+
+/// Identifies the output of a filter across process restarts.
+///
+/// The `origin` is the source file the output is derived from, and `params` is a stable hash
+/// of the effect (and its configuration) that produced the output.  The size and modification
+/// time of the origin are also taken into account when the output is looked up, so changes to
+/// the source file will not be served stale outputs.
+#[derive(Clone, Debug)]
+pub struct CacheKey {
+    origin: PathBuf,
+    params: u64,
+}
+
+/// Identifies the effect that produces the outputs to be cached, as the names of the types are
+/// not guaranteed to remain the same across builds.
+pub trait EffectId: Hash {
+    /// Unique to the effect, along with a version that must be bumped whenever the outputs it
+    /// produces with the same configuration change, e.g. `"effs-image::Resize/1"`.
+    const EFFECT_ID: &'static str;
+}
+
+impl CacheKey {
+    pub fn new<E: EffectId>(origin: impl Into<PathBuf>, effect: &E) -> Self {
+        let mut hasher = StableHasher::default();
+        E::EFFECT_ID.hash(&mut hasher);
+        effect.hash(&mut hasher);
+        Self {
+            origin: origin.into(),
+            params: hasher.finish(),
+        }
+    }
+
+    fn digest(&self) -> io::Result<u64> {
+        let metadata = fs::metadata(&self.origin)?;
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = StableHasher::default();
+        self.origin.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        mtime.as_nanos().hash(&mut hasher);
+        self.params.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+}
+
+/// FNV-1a, as the hash must remain the same across builds for the cache to be reusable.  The
+/// integers are hashed as little endian, and the sizes, such as the lengths of collections, as
+/// `u64`, so neither the endianness nor the pointer width of the target affects the hash.
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as i64 as u64);
+    }
+}
+
+/// A persistent cache of filtrated outputs, stored as files within a directory.
+///
+/// The least recently used outputs are removed once the total size exceeds the capacity.
+pub struct DiskCache {
+    root: PathBuf,
+    capacity: u64,
+    used: Mutex<u64>,
+    counter: AtomicU64,
+}
+
+impl DiskCache {
+    pub fn new(root: impl Into<PathBuf>, capacity: u64) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        let mut used = 0;
+        for entry in fs::read_dir(&root)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                // left behind by an interrupted write
+                fs::remove_file(entry.path()).ok();
+            } else {
+                used += entry.metadata()?.len();
+            }
+        }
+        Ok(Self {
+            root,
+            capacity,
+            used: Mutex::new(used),
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.as_path()
+    }
+
+    fn path_of(&self, key: &CacheKey) -> io::Result<PathBuf> {
+        Ok(self.root.join(format!("{:016x}", key.digest()?)))
+    }
+
+    fn get_blocking(&self, key: &CacheKey) -> io::Result<Option<Bytes>> {
+        let path = self.path_of(key)?;
+        match fs::read(&path) {
+            Ok(output) => {
+                // mark as recently used, which fails should the cache be read only, yet the
+                // output is still there to be used
+                File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()))
+                    .ok();
+                Ok(Some(output.into()))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put_blocking(&self, key: &CacheKey, output: &[u8]) -> io::Result<()> {
+        if output.len() as u64 > self.capacity {
+            return Ok(());
+        }
+        let path = self.path_of(key)?;
+        let tmp = self.root.join(format!(
+            "{TMP_PREFIX}{}-{}",
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::Relaxed),
+        ));
+        let written = File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(output)?;
+                file.sync_all()
+            });
+        if written.is_err() {
+            fs::remove_file(&tmp).ok();
+        }
+        written?;
+
+        // the output being replaced is only accounted for while nothing else may replace it
+        let mut used = self.used
+            .lock()
+            .expect("disk cache lock poisoned");
+        let replaced = fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        if let Err(e) = fs::rename(&tmp, &path) {
+            fs::remove_file(&tmp).ok();
+            return Err(e);
+        }
+        *used = used.saturating_sub(replaced) + output.len() as u64;
+        if *used > self.capacity {
+            *used = self.evict(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the least recently used outputs, other than the one at `keep`, until the total
+    /// size is within the capacity; returns the total size of the remaining outputs.
+    fn evict(&self, keep: &Path) -> io::Result<u64> {
+        let mut entries = fs::read_dir(&self.root)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect::<Vec<_>>();
+        let mut used = entries.iter()
+            .map(|(_, len, _)| len)
+            .sum::<u64>();
+        entries.sort();
+        for (_, len, path) in entries {
+            if used <= self.capacity {
+                break;
+            }
+            if path != keep && fs::remove_file(&path).is_ok() {
+                used -= len;
+            }
+        }
+        Ok(used)
+    }
+
+    pub async fn get(self: &Arc<Self>, key: &CacheKey) -> Option<Bytes> {
+        let cache = self.clone();
+        let key = key.clone();
+        spawn_blocking(move || cache.get_blocking(&key))
+            .await
+            .ok()?
+            .unwrap_or_else(|e| {
+                tracing::warn!("failed to read from disk cache: {e}");
+                None
+            })
+    }
+
+    pub async fn put(self: &Arc<Self>, key: &CacheKey, output: Bytes) {
+        let cache = self.clone();
+        let key = key.clone();
+        let result = spawn_blocking(move || cache.put_blocking(&key, &output))
+            .await;
+        if let Ok(Err(e)) = result {
+            tracing::warn!("failed to write to disk cache: {e}");
+        }
+    }
+
+    /// Produce a filter that will be served from the cache, if the filter has a key.
+    pub(crate) fn wrap(self: &Arc<Self>, filter: Filter) -> Filter {
+        let Some(key) = filter.key.clone() else {
+            return filter;
+        };
+        let cache = self.clone();
+        let inner = filter.clone();
+        Filter {
+            inner: Arc::new(move || {
+                let cache = cache.clone();
+                let key = key.clone();
+                let inner = inner.clone();
+                Filtrate::new(async move {
+                    if let Some(output) = cache.get(&key).await {
+                        return Ok(output);
+                    }
+                    let output = inner.filtrate().await?;
+                    cache.put(&key, output.clone()).await;
+                    Ok(output)
+                })
+            }),
+            ..filter
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[derive(Hash)]
+    struct Params(u32);
+
+    impl EffectId for Params {
+        const EFFECT_ID: &'static str = "effs::cache::disk::tests::Params/1";
+    }
+
+    #[test]
+    fn stable_hash() {
+        let mut hasher = StableHasher::default();
+        (1usize, -1isize, 0x0102u16, Some(3u32)).hash(&mut hasher);
+        let mut expected = StableHasher::default();
+        expected.write(&1u64.to_le_bytes());
+        expected.write(&u64::MAX.to_le_bytes());
+        expected.write(&[0x02, 0x01]);
+        // the discriminant of the variant, followed by the value
+        expected.write(&1u64.to_le_bytes());
+        expected.write(&3u32.to_le_bytes());
+        assert_eq!(hasher.finish(), expected.finish());
+    }
+
+    #[tokio::test]
+    async fn persisted() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let origin = root.path().join("origin");
+        fs::write(&origin, b"origin")?;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let filter = Filter::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Filtrate::new(async { Ok(Bytes::from_static(b"output")) })
+        }).with_key(CacheKey::new(&origin, &Params(1)));
+
+        let cache = Arc::new(DiskCache::new(root.path().join("cache"), 1024)?);
+        let wrapped = cache.wrap(filter.clone());
+        assert_eq!(wrapped.filtrate().await?, b"output".as_ref());
+        assert_eq!(wrapped.filtrate().await?, b"output".as_ref());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // the output survives the cache being reopened
+        let cache = Arc::new(DiskCache::new(root.path().join("cache"), 1024)?);
+        assert_eq!(cache.wrap(filter.clone()).filtrate().await?, b"output".as_ref());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // different parameters will not share the output
+        let other = filter.clone().with_key(CacheKey::new(&origin, &Params(2)));
+        cache.wrap(other).filtrate().await?;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // nor will a modified origin
+        fs::write(&origin, b"modified origin")?;
+        cache.wrap(filter).filtrate().await?;
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn eviction() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let cache = Arc::new(DiskCache::new(root.path().join("cache"), 10)?);
+        let keys = (0..3)
+            .map(|i| {
+                let origin = root.path().join(format!("origin{i}"));
+                fs::write(&origin, b"origin")?;
+                Ok(CacheKey::new(origin, &Params(0)))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        cache.put(&keys[0], Bytes::from_static(b"0000")).await;
+        File::options()
+            .write(true)
+            .open(cache.path_of(&keys[0])?)?
+            .set_modified(UNIX_EPOCH)?;
+        cache.put(&keys[1], Bytes::from_static(b"1111")).await;
+        cache.put(&keys[2], Bytes::from_static(b"2222")).await;
+        assert_eq!(cache.get(&keys[0]).await, None);
+        assert_eq!(cache.get(&keys[1]).await, Some(Bytes::from_static(b"1111")));
+        assert_eq!(cache.get(&keys[2]).await, Some(Bytes::from_static(b"2222")));
+        assert_eq!(*cache.used.lock().unwrap(), 8);
+
+        // replacing an output only accounts for the difference
+        cache.put(&keys[1], Bytes::from_static(b"11")).await;
+        assert_eq!(cache.get(&keys[1]).await, Some(Bytes::from_static(b"11")));
+        assert_eq!(*cache.used.lock().unwrap(), 6);
+
+        // too large to be cached
+        cache.put(&keys[0], Bytes::from_static(b"00000000000")).await;
+        assert_eq!(cache.get(&keys[0]).await, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    ffi::{
+        OsStr,
+        OsString,
+    },
+    path::Path,
+};
+
+use crate::entry::Entry;
+
+/// How the entries are resolved when more than one source produces the same name within the
+/// same directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conflict {
+    /// The entry from the source that was pushed first is used.
+    FirstWins,
+    /// The entry from the source that was pushed last is used.
+    LastWins,
+    /// Directories are merged, such that all the sources that produced them will be asked for
+    /// what is within; otherwise the entry from the source that was pushed last is used.
+    #[default]
+    Merge,
+    /// The entry from the source that was pushed first keeps the name, while the names of the
+    /// others are suffixed with the label of their source, e.g. `photo~label.jpg`.
+    Suffix,
+}
+
+/// An entry listed by a source for a directory.
+pub(crate) struct Listed {
+    pub(crate) name: OsString,
+    pub(crate) entry: Entry,
+    // whether the entry was freshly produced, rather than from a listing that was reused
+    pub(crate) fresh: bool,
+    // the index of the source that produced the entry, or none for the directories that lead
+    // to where a source is mounted
+    pub(crate) owner: Option<usize>,
+    // the name the source produced the entry as, should it be renamed to resolve a conflict
+    pub(crate) source_name: Option<OsString>,
+}
+
+impl Listed {
+    fn is_dir(&self) -> bool {
+        self.entry.is_dir()
+    }
+}
+
+impl Conflict {
+    /// Resolve the entries with the same name, where `labels` are the labels of the sources.
+    pub(crate) fn resolve(self, listing: Vec<Listed>, labels: &[String]) -> Vec<Listed> {
+        let mut order = Vec::new();
+        let mut by_name = HashMap::<OsString, Vec<Listed>>::new();
+        for listed in listing {
+            by_name.entry(listed.name.clone())
+                .or_insert_with(|| {
+                    order.push(listed.name.clone());
+                    Vec::new()
+                })
+                .push(listed);
+        }
+
+        let mut result = Vec::new();
+        for name in order {
+            let mut contenders = by_name.remove(&name)
+                .expect("every name in order has its contenders");
+            if contenders.iter().any(|listed| listed.owner.is_none()) {
+                // A source is mounted below, so this must remain a directory; sources that
+                // produced a directory here may still provide its attributes.
+                contenders.retain(|listed| listed.owner.is_some() && listed.is_dir());
+                if contenders.is_empty() {
+                    result.push(mount_point(name));
+                    continue;
+                }
+            }
+            if contenders.len() == 1 {
+                result.extend(contenders);
+                continue;
+            }
+            match self {
+                Conflict::FirstWins => result.push(contenders.remove(0)),
+                Conflict::LastWins => result.extend(contenders.pop()),
+                Conflict::Merge if contenders.iter().all(Listed::is_dir) => {
+                    result.push(contenders.remove(0));
+                }
+                Conflict::Merge => result.extend(contenders.pop()),
+                Conflict::Suffix => {
+                    let mut contenders = contenders.into_iter();
+                    result.extend(contenders.next());
+                    result.extend(contenders.map(|listed| {
+                        let label = listed.owner
+                            .and_then(|owner| labels.get(owner))
+                            .map(String::as_str)
+                            .unwrap_or_default();
+                        Listed {
+                            name: suffixed(&listed.name, label),
+                            source_name: Some(listed.name),
+                            ..listed
+                        }
+                    }));
+                }
+            }
+        }
+        result
+    }
+}
+
+fn mount_point(name: OsString) -> Listed {
+    Listed {
+        name,
+        entry: Entry::Dir(Default::default()),
+        fresh: false,
+        owner: None,
+        source_name: None,
+    }
+}
+
+fn suffixed(name: &OsStr, label: &str) -> OsString {
+    let path = Path::new(name);
+    let (mut result, extension) = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => (stem.to_os_string(), Some(extension)),
+        _ => (name.to_os_string(), None),
+    };
+    result.push("~");
+    result.push(label);
+    if let Some(extension) = extension {
+        result.push(".");
+        result.push(extension);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn listed(name: &str, owner: Option<usize>, dir: bool) -> Listed {
+        Listed {
+            name: name.into(),
+            entry: if dir {
+                Entry::Dir(Default::default())
+            } else {
+                Bytes::from(owner.map(|owner| owner.to_string()).unwrap_or_default()).into()
+            },
+            fresh: true,
+            owner,
+            source_name: None,
+        }
+    }
+
+    fn resolve(conflict: Conflict, listing: Vec<Listed>) -> Vec<(OsString, Option<usize>)> {
+        let labels = ["a".to_string(), "b".to_string(), "c".to_string()];
+        conflict.resolve(listing, &labels)
+            .into_iter()
+            .map(|listed| (listed.name, listed.owner))
+            .collect()
+    }
+
+    fn names(names: &[(&str, Option<usize>)]) -> Vec<(OsString, Option<usize>)> {
+        names.iter()
+            .map(|(name, owner)| (name.into(), *owner))
+            .collect()
+    }
+
+    #[test]
+    fn policies() {
+        let listing = || vec![
+            listed("file.txt", Some(0), false),
+            listed("dir", Some(0), true),
+            listed("only", Some(1), false),
+            listed("file.txt", Some(1), false),
+            listed("dir", Some(1), true),
+            listed("dir", Some(2), false),
+        ];
+        assert_eq!(
+            resolve(Conflict::FirstWins, listing()),
+            names(&[("file.txt", Some(0)), ("dir", Some(0)), ("only", Some(1))]),
+        );
+        assert_eq!(
+            resolve(Conflict::LastWins, listing()),
+            names(&[("file.txt", Some(1)), ("dir", Some(2)), ("only", Some(1))]),
+        );
+        // not every entry at dir is a directory
+        assert_eq!(
+            resolve(Conflict::Merge, listing()),
+            names(&[("file.txt", Some(1)), ("dir", Some(2)), ("only", Some(1))]),
+        );
+        assert_eq!(
+            resolve(Conflict::Merge, listing().into_iter().take(5).collect()),
+            names(&[("file.txt", Some(1)), ("dir", Some(0)), ("only", Some(1))]),
+        );
+        assert_eq!(
+            resolve(Conflict::Suffix, listing()),
+            names(&[
+                ("file.txt", Some(0)),
+                ("file~b.txt", Some(1)),
+                ("dir", Some(0)),
+                ("dir~b", Some(1)),
+                ("dir~c", Some(2)),
+                ("only", Some(1)),
+            ]),
+        );
+    }
+
+    #[test]
+    fn mount_point() {
+        // a file cannot replace where another source is mounted
+        let listing = vec![
+            listed("photos", Some(0), false),
+            listed("photos", None, true),
+        ];
+        let resolved = Conflict::LastWins.resolve(listing, &[]);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].owner, None);
+        assert!(resolved[0].is_dir());
+
+        let listing = vec![
+            listed("photos", None, true),
+            listed("photos", Some(1), true),
+        ];
+        let resolved = Conflict::FirstWins.resolve(listing, &[]);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].owner, Some(1));
+    }
+}
@@ -1,65 +1,390 @@
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
 use std::{
-    collections::BTreeMap,
     ffi::OsString,
-    fs::{
-        File,
-        read_dir,
-    },
-    io::Read as _,
+    fs::File,
+    io,
+    os::unix::fs::FileExt as _,
     path::Path,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use tokio::{
+    fs,
+    task::spawn_blocking,
 };
 
 use crate::{
-    entry::Entry,
-    error::EffectError,
-    filter::Filter,
-    future::Filtrate,
-    traits::Effect,
+    entry::{
+        Attr,
+        Dir,
+        Entry,
+    },
+    error::{
+        EffectError,
+        Error,
+    },
+    filter::PreciseFilter,
+    future::{
+        FileSize,
+        Filtrate,
+    },
+    traits::AsyncEffect,
 };
 
-pub struct Mirror;
+/// How the symbolic links found by `Mirror` are to be presented.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// As symbolic links to the same target.
+    #[default]
+    Preserve,
+    /// As whatever they point to; broken links are skipped.
+    Follow,
+    /// As whatever they point to, but only for the links that point to somewhere within the
+    /// origin of the source; all other links are skipped.
+    FollowWithin,
+}
 
-impl Effect for Mirror {
-    fn apply(&mut self, path: &Path, request: &Path) -> Result<Vec<(OsString, Entry)>, EffectError> {
-        // XXX assumes the incoming request will not be an absolute path
-        if !path.is_dir() {
-            return Err(EffectError::BadSourcePath(path.into(), "not a directory"))
-        }
-        let path = path.join(request);
-        if !path.is_dir() {
-            return Err(EffectError::BadRequestPath(request.into(), "not a directory"))
-        }
+/// Presents the files and directories at the origin as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Mirror {
+    symlinks: Symlinks,
+}
+
+impl Mirror {
+    pub fn with_symlinks(mut self, symlinks: Symlinks) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+}
 
-        Ok(read_dir(path)?
-            .filter_map(|res| {
-                res.map(|e| {
-                    e.file_type()
-                        .map(|file_type| {
-                            if file_type.is_dir() {
-                                Some((e.file_name(), Entry::Dir(Default::default())))
-                            } else if file_type.is_file() {
-                                Some((e.file_name(), Entry::Filter(Filter::new(move || {
-                                    let path = e.path();
-                                    Filtrate::new(
-                                        async move {
-                                            let mut file = File::open(&path)?;
-                                            let mut output = Vec::new();
-                                            file.read_to_end(&mut output)?;
-                                            Ok(output.into())
-                                        }
-                                    )
-                                }))))
-                            } else {
-                                None
+impl AsyncEffect for Mirror {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        let symlinks = self.symlinks;
+        Box::pin(async move {
+            // XXX assumes the incoming request will not be an absolute path
+            if !is_dir(origin).await {
+                return Err(EffectError::BadSourcePath(origin.into(), "not a directory"))
+            }
+            let path = origin.join(request);
+            if !is_dir(&path).await {
+                return Err(EffectError::BadRequestPath(request.into(), "not a directory"))
+            }
+
+            let mut result = Vec::new();
+            let mut entries = fs::read_dir(path).await?;
+            while let Some(e) = entries.next_entry().await? {
+                let Ok(mut metadata) = e.metadata().await else {
+                    continue;
+                };
+                if metadata.file_type().is_symlink() {
+                    let followed = match symlinks {
+                        Symlinks::Preserve => {
+                            if let Ok(target) = fs::read_link(e.path()).await {
+                                result.push((e.file_name(), Entry::Symlink(target)));
+                            }
+                            continue;
+                        }
+                        Symlinks::Follow => fs::metadata(e.path()).await.ok(),
+                        Symlinks::FollowWithin if is_within(origin, &e.path()).await => {
+                            fs::metadata(e.path()).await.ok()
+                        }
+                        Symlinks::FollowWithin => None,
+                    };
+                    let Some(followed) = followed else {
+                        continue;
+                    };
+                    metadata = followed;
+                }
+                let file_type = metadata.file_type();
+                let attr = Attr::from(&metadata);
+                if file_type.is_dir() {
+                    result.push((e.file_name(), Dir::default().with_attr(attr).into()));
+                } else if file_type.is_file() {
+                    let path = Arc::new(e.path());
+                    let open_path = path.clone();
+                    let size_path = e.path();
+                    result.push((e.file_name(), Entry::PreciseFilter(PreciseFilter::new(move |offset, size| {
+                        let path = path.clone();
+                        Filtrate::new(
+                            async move {
+                                spawn_blocking(move || read_at(&File::open(&*path)?, offset, size))
+                                    .await
+                                    .map_err(|_| Error::Internal)?
                             }
-                        })
-                        .ok()
-                        .flatten()
-                })
-                    .ok()
-                    .flatten()
+                        )
+                    }).with_open(move || {
+                        // the file is opened by the first read through the handle, and is kept
+                        // open for the reads that follow
+                        let path = open_path.clone();
+                        let file = Arc::new(Mutex::new(None::<Arc<File>>));
+                        move |offset, size| {
+                            let path = path.clone();
+                            let file = file.clone();
+                            Filtrate::new(
+                                async move {
+                                    spawn_blocking(move || {
+                                        let file = {
+                                            let mut file = file.lock().expect("file lock poisoned");
+                                            match &*file {
+                                                Some(file) => file.clone(),
+                                                None => file.insert(Arc::new(File::open(&*path)?)).clone(),
+                                            }
+                                        };
+                                        read_at(&file, offset, size)
+                                    })
+                                        .await
+                                        .map_err(|_| Error::Internal)?
+                                }
+                            )
+                        }
+                    }).with_size(move || {
+                        let path = size_path.clone();
+                        FileSize::new(
+                            async move {
+                                Ok(fs::metadata(&path).await?.len())
+                            }
+                        )
+                    }).with_attr(attr))));
+                }
+            }
+            Ok(result)
+        })
+    }
+}
+
+/// Read up to `size` bytes starting at `offset`, with fewer bytes returned only if the end
+/// of the file is reached.  A file that cannot be read positionally, such as a fifo, fails.
+fn read_at(file: &File, offset: u64, size: u32) -> Result<Bytes, Error> {
+    let mut output = vec![0; size as usize];
+    let mut len = 0;
+    while len < output.len() {
+        match file.read_at(&mut output[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    output.truncate(len);
+    Ok(output.into())
+}
+
+/// Whether the path resolves to somewhere within the root.
+async fn is_within(root: &Path, path: &Path) -> bool {
+    match (fs::canonicalize(root).await, fs::canonicalize(path).await) {
+        (Ok(root), Ok(path)) => path.starts_with(root),
+        _ => false,
+    }
+}
+
+async fn is_dir(path: &Path) -> bool {
+    fs::metadata(path)
+        .await
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        os::unix::ffi::OsStrExt as _,
+    };
+    use futures_util::FutureExt as _;
+    use tempfile::tempdir;
+    use tokio::io::AsyncWriteExt as _;
+
+    use crate::{
+        handle::Handle,
+        node::Nodes,
+    };
+    use super::*;
+
+    async fn filter(root: &Path, name: &str) -> anyhow::Result<PreciseFilter> {
+        let listing = Mirror::default().apply(root, Path::new("")).await?;
+        listing.into_iter()
+            .find_map(|(n, entry)| match entry {
+                Entry::PreciseFilter(filter) if n == name => Some(filter),
+                _ => None,
             })
-            .collect::<Vec<_>>()
-        )
+            .ok_or_else(|| anyhow::anyhow!("{name} not found"))
+    }
+
+    #[tokio::test]
+    async fn precise_reads() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::write(root.path().join("data"), b"0123456789")?;
+        let filter = filter(root.path(), "data").await?;
+        assert_eq!(filter.file_size().expect("size is provided").await?, 10);
+        assert_eq!(filter.filtrate(0, 4).await?, b"0123".as_ref());
+        assert_eq!(filter.filtrate(8, 4).await?, b"89".as_ref());
+        assert_eq!(filter.filtrate(10, 4).await?, b"".as_ref());
+        assert_eq!(filter.filtrate(100, 4).await?, b"".as_ref());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_reads() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let data = root.path().join("data");
+        std::fs::write(&data, b"0123456789")?;
+        let filter = filter(root.path(), "data").await?;
+        let handle = Handle::new(filter.clone().into());
+        assert_eq!(handle.read(0, 4).await?, b"0123".as_ref());
+
+        // the file opened by the handle is kept open, so it is still read once it is removed
+        std::fs::remove_file(&data)?;
+        assert_eq!(handle.read(4, 4).await?, b"4567".as_ref());
+        assert!(filter.filtrate(4, 4).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_reads() -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .max_blocking_threads(1)
+            .build()?;
+        runtime.block_on(async {
+            let root = tempdir()?;
+            std::fs::write(root.path().join("data"), b"0123456789")?;
+            let filter = filter(root.path(), "data").await?;
+
+            // the only blocking thread is kept busy, so the read can only be pending rather
+            // than complete, as it would be had it blocked the executor
+            let (release, released) = std::sync::mpsc::channel();
+            let busy = spawn_blocking(move || released.recv());
+            let mut read = filter.filtrate(2, 4);
+            assert!((&mut read).now_or_never().is_none());
+            release.send(())?;
+            busy.await??;
+            assert_eq!(read.await?, b"2345".as_ref());
+            anyhow::Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn unseekable() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let data = root.path().join("data");
+        std::fs::write(&data, b"")?;
+        let filter = filter(root.path(), "data").await?;
+
+        // a fifo cannot be read positionally, so rather than being read from wherever it is
+        // at, as if that were the requested offset, the read fails
+        std::fs::remove_file(&data)?;
+        let fifo = CString::new(data.as_os_str().as_bytes())?;
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let (read, write) = tokio::join!(
+            filter.filtrate(0, 7),
+            async {
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .open(&data)
+                    .await?;
+                file.write_all(b"written").await
+            },
+        );
+        write?;
+        assert!(read.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attr() -> anyhow::Result<()> {
+        use std::{
+            os::unix::fs::{
+                MetadataExt as _,
+                PermissionsExt as _,
+            },
+            time::{
+                Duration,
+                UNIX_EPOCH,
+            },
+        };
+
+        let root = tempdir()?;
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let data = root.path().join("data");
+        let file = std::fs::File::create(&data)?;
+        file.set_modified(mtime)?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        std::fs::create_dir(root.path().join("dir"))?;
+
+        let listing = Mirror::default().apply(root.path(), Path::new("")).await?;
+        let mut nodes = Nodes::default();
+        let root_id = nodes.node_id(1)?;
+        for (name, entry) in listing {
+            let metadata = std::fs::metadata(root.path().join(&name))?;
+            let attr = entry.attr().expect("mirrored entries have attributes");
+            assert_eq!(attr, &Attr::from(&metadata));
+
+            let node_id = nodes.link_entry(root_id, name.clone(), entry)?;
+            let (_, file_attr) = nodes.attr_for_node_id(node_id)?;
+            assert_eq!(file_attr.mtime, metadata.modified()?.into());
+            assert_eq!(file_attr.uid, metadata.uid());
+            if name == "data" {
+                assert_eq!(file_attr.mtime, mtime.into());
+                assert_eq!(file_attr.perm & 0o7777, 0o600);
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn symlinks() -> anyhow::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let root = tempdir()?;
+        let outside = tempdir()?;
+        std::fs::write(root.path().join("data"), b"data")?;
+        std::fs::write(outside.path().join("data"), b"outside")?;
+        symlink("data", root.path().join("inside"))?;
+        symlink(outside.path().join("data"), root.path().join("outside"))?;
+        symlink("missing", root.path().join("broken"))?;
+
+        let origin = root.path();
+        let listing = |symlinks| async move {
+            let mut listing = Mirror::default()
+                .with_symlinks(symlinks)
+                .apply(origin, Path::new(""))
+                .await?
+                .into_iter()
+                .map(|(name, entry)| (name.into_string().expect("names are utf-8"), match entry {
+                    Entry::Symlink(target) => format!("-> {}", target.display()),
+                    Entry::PreciseFilter(_) => "file".to_string(),
+                    _ => "other".to_string(),
+                }))
+                .collect::<Vec<_>>();
+            listing.sort();
+            anyhow::Ok(listing)
+        };
+        let expected = |expected: &[(&str, &str)]| expected.iter()
+            .map(|(name, kind)| (name.to_string(), kind.to_string()))
+            .collect::<Vec<_>>();
+        let outside_target = format!("-> {}", outside.path().join("data").display());
+
+        assert_eq!(listing(Symlinks::Preserve).await?, expected(&[
+            ("broken", "-> missing"),
+            ("data", "file"),
+            ("inside", "-> data"),
+            ("outside", &outside_target),
+        ]));
+        assert_eq!(listing(Symlinks::Follow).await?, expected(&[
+            ("data", "file"),
+            ("inside", "file"),
+            ("outside", "file"),
+        ]));
+        assert_eq!(listing(Symlinks::FollowWithin).await?, expected(&[
+            ("data", "file"),
+            ("inside", "file"),
+        ]));
+        Ok(())
     }
 }
@@ -1,72 +1,360 @@
+use bytes::BytesMut;
+use fuse3::notify::Notify;
+use futures_util::{
+    StreamExt,
+    future,
+};
 use indextree::NodeId;
 use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    ffi::OsString,
     path::{
         Component,
         Path,
         PathBuf,
     },
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::sync::{
+    Mutex as AsyncMutex,
+    RwLock,
 };
-use tokio::sync::RwLock;
 
 use crate::{
-    error::Error,
-    node::{
-        Node,
-        Nodes,
+    cache::{
+        DiskCache,
+        FiltrateCache,
+    },
+    conflict::{
+        Conflict,
+        Listed,
+    },
+    entry::{
+        Dir,
+        Entry,
+        LazyDir,
+    },
+    error::{
+        EffectError,
+        Error,
+        NoSuchNode,
+        SourceError,
     },
-    traits::EffsSource,
+    handle::Handles,
+    node::Nodes,
+    traits::AsyncEffsSource,
 };
+#[cfg(feature = "watch")]
+use crate::watch::Watcher;
 
 mod fs;
 
 pub struct Effs {
-    sources: RwLock<Vec<Box<dyn EffsSource>>>,
-    nodes: RwLock<Nodes>,
+    sources: RwLock<Vec<Arc<SourceSlot>>>,
+    nodes: Arc<RwLock<Nodes>>,
+    handles: Mutex<Handles>,
+    cache: Mutex<FiltrateCache>,
+    disk_cache: Option<Arc<DiskCache>>,
+    conflict: Conflict,
+    // for invalidating what the kernel has cached, once one is provided through `poll`
+    notify: Arc<Mutex<Option<Notify>>>,
+    #[cfg(feature = "watch")]
+    watcher: Option<Watcher>,
 }
 
-impl Default for Effs {
-    fn default() -> Self {
-        Self {
+/// When the listings produced by a source are to be produced again, as a directory is rebuilt
+/// every time it is listed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Refresh {
+    /// The source will be asked for the listing every time.
+    #[default]
+    Always,
+    /// The listing is reused until it is older than the duration.
+    Ttl(Duration),
+    /// The listing is only ever produced once, even if the directory is invalidated.
+    Once,
+    /// The listing is reused until the directory is invalidated, either through
+    /// `Effs::invalidate` or by the origin of the source being modified while it is watched.
+    Explicit,
+}
+
+/// A source, where the `dest_path` is kept outside of its lock such that finding the sources
+/// for a path need not wait on the sources that are producing listings for some other path.
+struct SourceSlot {
+    dest_path: PathBuf,
+    label: String,
+    state: AsyncMutex<SourceState>,
+}
+
+/// What a source is asked for when a directory is built, where `Some(None)` is for directories
+/// that are produced by something else, and `None` for where the source is not mounted at or
+/// below the directory.
+type SourceRequest = Option<Option<PathBuf>>;
+
+/// The lazy directory that produces the children of a directory, along with the source that
+/// produced it, if it is one.
+type LazyRequest = Option<(LazyDir, Option<usize>)>;
+
+struct SourceState {
+    source: Box<dyn AsyncEffsSource>,
+    refresh: Refresh,
+    // the listings last produced by the source, by the path they were produced for
+    listings: HashMap<PathBuf, (Instant, Vec<(OsString, Entry)>)>,
+}
+
+impl SourceState {
+    fn listing(&self, path: &Path, now: Instant) -> Option<&[(OsString, Entry)]> {
+        let (listed, listing) = self.listings.get(path)?;
+        match self.refresh {
+            Refresh::Always => None,
+            Refresh::Ttl(ttl) => (now.duration_since(*listed) < ttl).then_some(listing),
+            Refresh::Once | Refresh::Explicit => Some(listing),
+        }
+    }
+
+    fn invalidate(&mut self, path: &Path) {
+        if self.refresh != Refresh::Once {
+            self.listings.remove(path);
+        }
+    }
+}
+
+/// Configures the options for an `Effs`.
+#[derive(Default)]
+pub struct EffsBuilder {
+    cache_size: usize,
+    disk_cache: Option<DiskCache>,
+    conflict: Conflict,
+    #[cfg(feature = "watch")]
+    watch: bool,
+}
+
+impl EffsBuilder {
+    /// The maximum number of bytes of filtrated outputs to be kept in memory after all handles
+    /// to them are released, such that reopening the same file will not produce them again.
+    /// Only the outputs of `Filter` and `StreamFilter` entries are kept, as `PreciseFilter`
+    /// entries, such as those from `Mirror`, are read directly from their origin.
+    /// Defaults to 0, which disables the cache.
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Persist the outputs of filters that provide a `CacheKey` with the disk cache, so they
+    /// will not need to be produced again after a remount.  The entries without a key, such as
+    /// those from `Mirror`, are never persisted.
+    pub fn disk_cache(mut self, disk_cache: DiskCache) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    /// How the entries are resolved when multiple sources produce the same name within the same
+    /// directory.  Defaults to `Conflict::Merge`.
+    pub fn conflict(mut self, conflict: Conflict) -> Self {
+        self.conflict = conflict;
+        self
+    }
+
+    /// Watch the origins of the sources for changes, such that the directories built from them
+    /// will be rebuilt when next accessed after a change.  Defaults to false.
+    ///
+    /// The kernel caches what it was given for a while, and fuse3 only allows it to be told of
+    /// the changes once a file has been polled, so the entries and attributes are only cached
+    /// by the kernel for a much shorter time than usual until then.
+    #[cfg(feature = "watch")]
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    pub fn build(self) -> Effs {
+        let nodes = Arc::new(RwLock::new(Nodes::default()));
+        let notify = Arc::new(Mutex::new(None));
+        #[cfg(feature = "watch")]
+        let watcher = self.watch
+            .then(|| Watcher::new(nodes.clone(), notify.clone()))
+            .and_then(|watcher| watcher
+                .inspect_err(|e| tracing::warn!("failed to watch for changes: {e}"))
+                .ok()
+            );
+        Effs {
             sources: RwLock::new(Vec::new()),
-            nodes: RwLock::new(Nodes::default()),
+            nodes,
+            handles: Mutex::new(Handles::default()),
+            cache: Mutex::new(FiltrateCache::new(self.cache_size)),
+            disk_cache: self.disk_cache.map(Arc::new),
+            conflict: self.conflict,
+            notify,
+            #[cfg(feature = "watch")]
+            watcher,
         }
     }
 }
 
+impl Default for Effs {
+    fn default() -> Self {
+        EffsBuilder::default().build()
+    }
+}
+
 impl Effs {
-    pub async fn push_source(&self, source: impl EffsSource) -> Result<(), Error> {
+    pub fn builder() -> EffsBuilder {
+        EffsBuilder::default()
+    }
+
+    pub async fn push_source(&self, source: impl AsyncEffsSource) -> Result<(), Error> {
+        self.push_source_with_refresh(source, Refresh::default()).await
+    }
+
+    pub async fn push_source_with_refresh(
+        &self,
+        source: impl AsyncEffsSource,
+        refresh: Refresh,
+    ) -> Result<(), Error> {
         let mut sources = self.sources
             .write()
             .await;
-        sources.push(Box::new(source));
+        let label = source.label()
+            .map(String::from)
+            .unwrap_or_else(|| sources.len().to_string());
+        sources.push(Arc::new(SourceSlot {
+            dest_path: source.dest_path().to_path_buf(),
+            label,
+            state: AsyncMutex::new(SourceState {
+                source: Box::new(source),
+                refresh,
+                listings: HashMap::new(),
+            }),
+        }));
         Ok(())
     }
 
-    pub(crate) async fn path_of_inode(&self, inode: u64) -> Result<PathBuf, Error> {
-        let nodes = self.nodes
+    /// Mark the directory at the path as modified, such that the sources will be asked for
+    /// their listings when it is next accessed, unless their refresh policy is `Refresh::Once`.
+    pub async fn invalidate(&self, path: &Path) -> Result<(), Error> {
+        let mut nodes = self.nodes
+            .write()
+            .await;
+        let node_id = nodes.path_to_node_id(path)?;
+        nodes[node_id].dirty = true;
+        Ok(())
+    }
+
+    /// The label of the source that produced the entry at the path, which is none for the
+    /// directories that only lead to where sources are mounted.
+    pub async fn owner(&self, path: &Path) -> Result<Option<String>, Error> {
+        let owner = {
+            let nodes = self.nodes
+                .read()
+                .await;
+            nodes[nodes.path_to_node_id(path)?].owner
+        };
+        let sources = self.sources
             .read()
             .await;
-        Ok(nodes.path_of_inode(inode)?)
+        Ok(owner.and_then(|owner| sources.get(owner))
+            .map(|slot| slot.label.clone()))
     }
 
     async fn path_to_node_id(&self, path: &Path) -> Result<NodeId, Error> {
-        let nodes = self.nodes
+        self.nodes
             .read()
-            .await;
+            .await
+            .path_to_node_id(path)
+    }
 
-        let mut comps = path.components().peekable();
-        if comps.peek() == Some(&Component::RootDir) {
-            // discard the root component
-            comps.next();
-        }
-        let mut result = nodes.basic_node_id(1)?;
-        // XXX this assumes the incoming path is fully normalize, i.e. without
-        // Component::ParentDir in the mix
-        while let Some(Component::Normal(fragment)) = comps.next() {
-            result = nodes.basic_lookup_node_id_name(result, fragment)?;
-        }
+    /// Ensure the size of the node is known, resolving it through the underlying filter
+    /// if it has not been determined yet, and cache the result on the node.
+    pub(crate) async fn resolve_size(&self, node_id: NodeId) -> Result<(), Error> {
+        let inode = usize::from(node_id) as u64;
+        let (entry, generation) = {
+            let nodes = self.nodes
+                .read()
+                .await;
+            if node_id.is_removed(&nodes.0) {
+                return Err(NoSuchNode(inode).into());
+            }
+            let node = &nodes[node_id];
+            if node.size.is_some() {
+                return Ok(());
+            }
+            (node.entry.clone(), node.generation)
+        };
+        let size = match entry {
+            Some(Entry::Filter(f)) => match f.file_size() {
+                Some(size) => size.await?,
+                None => {
+                    let cached = self.cache()
+                        .get(inode, generation);
+                    let filtrate = match cached {
+                        Some(filtrate) => filtrate,
+                        None => f.filtrate().await?,
+                    };
+                    let size = filtrate.len() as u64;
+                    // the file is likely about to be opened, so keep the output for that
+                    // should the cache have room for it
+                    self.cache()
+                        .insert(inode, generation, filtrate);
+                    size
+                }
+            },
+            Some(Entry::PreciseFilter(f)) => match f.file_size() {
+                Some(size) => size.await?,
+                // there is no way to know the size without a hint
+                None => return Ok(()),
+            },
+            Some(Entry::StreamFilter(f)) => match f.file_size() {
+                Some(size) => size.await?,
+                None => {
+                    let cached = self.cache()
+                        .get(inode, generation);
+                    match cached {
+                        Some(filtrate) => filtrate.len() as u64,
+                        None => {
+                            let mut stream = f.filtrate();
+                            match stream.known_len() {
+                                Some(len) => len,
+                                None => {
+                                    let mut output = BytesMut::new();
+                                    while let Some(chunk) = stream.next().await {
+                                        output.extend_from_slice(&chunk?);
+                                    }
+                                    // the whole output was produced, so keep it for the file
+                                    // being opened as for a filter
+                                    let output = output.freeze();
+                                    let len = output.len() as u64;
+                                    self.cache()
+                                        .insert(inode, generation, output);
+                                    len
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            _ => return Ok(()),
+        };
 
-        Ok(result)
+        let mut nodes = self.nodes
+            .write()
+            .await;
+        // only cache the size if the node was not removed or relinked while the size was
+        // resolved
+        if !node_id.is_removed(&nodes.0) && nodes[node_id].generation == generation {
+            nodes[node_id].size = Some(size);
+        }
+        Ok(())
     }
 
     pub async fn build_nodes(&self, path: &Path) -> Result<(), Error> {
@@ -78,33 +366,465 @@ impl Effs {
         };
         let par_node_id = self.path_to_node_id(path).await?;
 
-        let mut sources = self.sources
-            .write()
-            .await;
+        // The listings are produced without holding the lock on the nodes, so that other
+        // requests may be served while the sources are producing them, and every source is
+        // only locked while it is producing its own listing.
+        let sources = self.sources
+            .read()
+            .await
+            .clone();
+        let (requests, lazy) = {
+            let nodes = self.nodes
+                .read()
+                .await;
+            self.requests(&nodes, &sources, par_node_id, path)?
+        };
+        let (mut listings, lazy_listing) = future::join(
+            future::join_all(sources.iter()
+                .zip(requests)
+                .enumerate()
+                .map(|(i, (slot, request))| self.list_source(i, slot, path, request))),
+            async {
+                let (lazy, owner) = lazy?;
+                Some(match lazy.listing().await {
+                    Ok((listing, fresh)) => Some(listing.into_iter()
+                        .map(|(name, entry)| Listed {
+                            name,
+                            entry,
+                            fresh,
+                            owner,
+                            source_name: None,
+                        })
+                        .collect()),
+                    Err(e) => {
+                        tracing::warn!("lazy directory failed to list {path:?}: {e}");
+                        None
+                    }
+                })
+            },
+        ).await;
+        listings.extend(lazy_listing);
+        // only when every source produced their listing may the nodes that are no longer
+        // listed be removed, as otherwise some source may simply be failing temporarily
+        let complete = listings.iter().all(Option::is_some);
+        let labels = sources.iter()
+            .map(|slot| slot.label.clone())
+            .collect::<Vec<_>>();
+        let process = self.conflict.resolve(
+            listings.into_iter()
+                .flatten()
+                .flatten()
+                .collect(),
+            &labels,
+        );
+
         let mut nodes = self.nodes
             .write()
             .await;
-        let process = sources.iter_mut()
-            .filter_map(|source| {
-                // TODO figure out how to deal with error here
-                // TODO should probably log the error
-                source.dir(path)
-                    .map(|r| r.into_iter())
-                    .ok()
-            })
-            .flatten();
-        for (name, entry) in process {
+        if par_node_id.is_removed(&nodes.0) {
+            // the directory was removed while the listing was produced
+            return Err(NoSuchNode(usize::from(par_node_id) as u64).into());
+        }
+        let mut pruned = false;
+        if complete {
+            let listing = process.iter()
+                .map(|listed| (listed.name.as_os_str(), &listed.entry));
+            for inode in nodes.reconcile(par_node_id, listing) {
+                self.discard_output(inode);
+                pruned = true;
+            }
+            nodes[par_node_id].built = true;
+        }
+        for Listed { name, entry, fresh, owner, source_name } in process {
+            // the entries that were reused, or are unchanged since they were last produced,
+            // are kept as they were along with their cached outputs
+            let existing = nodes.basic_lookup_node_id_name(par_node_id, &name)
+                .map(|node_id| &nodes[node_id]);
+            if existing.is_ok_and(|node| node.owner == owner && (!fresh || node.unchanged(&entry))) {
+                continue;
+            }
+            let entry = match (entry, &self.disk_cache) {
+                (Entry::Filter(filter), Some(disk_cache)) => Entry::Filter(disk_cache.wrap(filter)),
+                (entry, _) => entry,
+            };
             // TODO should probably log the error
-            nodes.link_entry(par_node_id, name, entry).ok();
+            if let Ok(node_id) = nodes.link_entry(par_node_id, name, entry) {
+                let node = &mut nodes[node_id];
+                node.owner = owner;
+                node.source_name = source_name;
+                // the node has a new generation so the kept output will never be used
+                self.discard_output(usize::from(node_id) as u64);
+            }
+        }
+
+        // the listings kept for the directories that were removed will never be used again,
+        // nor will their origins need to be watched
+        if pruned {
+            let dirs = par_node_id.children(&nodes.0)
+                .filter(|nid| matches!(nodes[*nid].entry, Some(Entry::Dir(_))))
+                .map(|nid| nodes[nid].name.clone())
+                .collect::<HashSet<_>>();
+            drop(nodes);
+            let removed = |dir: &Path| match dir.strip_prefix(path)
+                .ok()
+                .and_then(|rest| rest.components().next())
+            {
+                Some(Component::Normal(name)) => !dirs.contains(name),
+                _ => false,
+            };
+            #[cfg(feature = "watch")]
+            if let Some(watcher) = &self.watcher {
+                watcher.unwatch(removed);
+            }
+            for slot in sources.iter() {
+                slot.state
+                    .lock()
+                    .await
+                    .listings
+                    .retain(|dir, _| !removed(dir));
+            }
+        }
+        Ok(())
+    }
+
+    /// The requests for the listings of the directory at the path from each of the sources, as
+    /// taken by `list_source`, along with the lazy directory that produces its children and
+    /// the source that produced it, if it is one.  The directory may have been removed since
+    /// its node was looked up, as the lock on the nodes is not held throughout.
+    fn requests(
+        &self,
+        nodes: &Nodes,
+        sources: &[Arc<SourceSlot>],
+        node_id: NodeId,
+        path: &Path,
+    ) -> Result<(Vec<SourceRequest>, LazyRequest), Error> {
+        if node_id.is_removed(&nodes.0) {
+            return Err(NoSuchNode(usize::from(node_id) as u64).into());
+        }
+        let node = &nodes[node_id];
+        // the children of a lazy directory are produced by the directory itself rather
+        // than by the source that produced it
+        let lazy = match &node.entry {
+            Some(Entry::Dir(Dir { lazy: Some(lazy), .. })) => Some((lazy.clone(), node.owner)),
+            _ => None,
+        };
+        let requests = sources.iter()
+            .enumerate()
+            .map(|(i, slot)| path.strip_prefix(&slot.dest_path)
+                .ok()
+                .map(|request| match lazy {
+                    Some((_, owner)) if owner == Some(i) => None,
+                    _ => nodes.request_path(
+                        node_id,
+                        request.components().count(),
+                        i,
+                        self.conflict == Conflict::Merge,
+                    ),
+                })
+            )
+            .collect();
+        Ok((requests, lazy))
+    }
+
+    /// Whether building the directory may find entries that it does not already have, as it
+    /// was never completely built or was marked as dirty since, or the refresh policy of some
+    /// source requires its listing to be produced again.
+    pub(crate) async fn stale(&self, node_id: NodeId) -> bool {
+        let sources = self.sources
+            .read()
+            .await
+            .clone();
+        let (path, requests) = {
+            let nodes = self.nodes
+                .read()
+                .await;
+            if node_id.is_removed(&nodes.0) {
+                return false;
+            }
+            if !nodes[node_id].built || nodes[node_id].dirty {
+                return true;
+            }
+            let Ok(path) = nodes.path_of_inode(usize::from(node_id) as u64) else {
+                return false;
+            };
+            let Ok((requests, _)) = self.requests(&nodes, &sources, node_id, &path) else {
+                return false;
+            };
+            (path, requests)
+        };
+        let now = Instant::now();
+        for (slot, request) in sources.iter().zip(requests) {
+            if !matches!(request, Some(Some(_))) {
+                continue;
+            }
+            if slot.state.lock().await.listing(&path, now).is_none() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Produce the listing of the source at the index for the path, where `request` is the
+    /// path within the source should the path be at or below its `dest_path`.  Returns `None`
+    /// should the source fail to produce the listing.
+    async fn list_source(
+        &self,
+        index: usize,
+        slot: &SourceSlot,
+        path: &Path,
+        request: SourceRequest,
+    ) -> Option<Vec<Listed>> {
+        let listed = |(name, entry), fresh| Listed {
+            name,
+            entry,
+            fresh,
+            owner: Some(index),
+            source_name: None,
+        };
+        let request = match request {
+            Some(Some(request)) => request,
+            // the path is within what is produced by some other source
+            Some(None) => return Some(Vec::new()),
+            // The source may be mounted somewhere below the requested path, so the next
+            // component towards its dest_path must be provided as a directory.
+            None => return Some(match slot.dest_path.strip_prefix(path)
+                .ok()
+                .and_then(|rest| rest.components().next())
+            {
+                Some(Component::Normal(name)) => vec![Listed {
+                    owner: None,
+                    ..listed((name.to_os_string(), Entry::Dir(Default::default())), false)
+                }],
+                _ => Vec::new(),
+            }),
+        };
+
+        let mut state = slot.state
+            .lock()
+            .await;
+        let now = Instant::now();
+        if let Some(listing) = state.listing(path, now) {
+            return Some(listing.iter()
+                .cloned()
+                .map(|item| listed(item, false))
+                .collect());
+        }
+        let listing = match state.source.dir(&request).await {
+            Ok(listing) => {
+                #[cfg(feature = "watch")]
+                self.watch(state.source.origin(), &request, path);
+                listing
+            }
+            // the source does not provide anything at the request
+            Err(SourceError::BadRequestPath(..))
+            | Err(SourceError::Effect(EffectError::BadRequestPath(..))) => Vec::new(),
+            Err(e) => {
+                tracing::warn!("source failed to list {path:?}: {e}");
+                return None;
+            }
+        };
+        // the listing would never be reused
+        if state.refresh == Refresh::Always {
+            return Some(listing.into_iter()
+                .map(|item| listed(item, true))
+                .collect());
+        }
+        let result = listing.iter()
+            .cloned()
+            .map(|item| listed(item, true))
+            .collect();
+        state.listings.insert(path.to_path_buf(), (now, listing));
+        Some(result)
+    }
+
+    /// Rebuild the directory, where the sources will be asked for their listings as their
+    /// refresh policies require, or if the directory was marked as dirty since it was last
+    /// built.
+    pub(crate) async fn refresh(&self, node_id: NodeId) -> Result<(), Error> {
+        let (path, dirty) = {
+            let mut nodes = self.nodes
+                .write()
+                .await;
+            if node_id.is_removed(&nodes.0) {
+                return Err(NoSuchNode(usize::from(node_id) as u64).into());
+            }
+            // cleared before the rebuild, so changes made during the rebuild are not missed
+            let dirty = std::mem::take(&mut nodes[node_id].dirty);
+            (nodes.path_of_inode(usize::from(node_id) as u64)?, dirty)
+        };
+        if dirty {
+            let sources = self.sources
+                .read()
+                .await
+                .clone();
+            for slot in sources {
+                slot.state
+                    .lock()
+                    .await
+                    .invalidate(&path);
+            }
+        }
+        let result = self.build_nodes(&path).await;
+        if result.is_err() && dirty {
+            let mut nodes = self.nodes
+                .write()
+                .await;
+            if !node_id.is_removed(&nodes.0) {
+                nodes[node_id].dirty = true;
+            }
+        }
+        result
+    }
+
+    /// Rebuild the directory only if it was marked as dirty since it was last built.
+    pub(crate) async fn refresh_dirty(&self, node_id: NodeId) -> Result<(), Error> {
+        let dirty = {
+            let nodes = self.nodes
+                .read()
+                .await;
+            !node_id.is_removed(&nodes.0) && nodes[node_id].dirty
+        };
+        if dirty {
+            self.refresh(node_id).await?;
         }
         Ok(())
     }
+
+    /// Rebuild the parent of the node if it was marked as dirty, as the node itself may have
+    /// been modified or removed.
+    pub(crate) async fn refresh_parent(&self, node_id: NodeId) -> Result<(), Error> {
+        let parent = node_id.parent(&self.nodes.read().await.0);
+        match parent {
+            Some(parent) => self.refresh_dirty(parent).await,
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "watch")]
+    fn watch(&self, origin: Option<&Path>, request: &Path, path: &Path) {
+        let (Some(watcher), Some(origin)) = (&self.watcher, origin) else {
+            return;
+        };
+        // avoid the trailing separator, as the origin may be a file
+        let origin = if request == Path::new("") {
+            origin.to_path_buf()
+        } else {
+            origin.join(request)
+        };
+        if let Err(e) = watcher.watch(&origin, path) {
+            tracing::debug!("failed to watch {origin:?}: {e}");
+        }
+    }
+
+    pub(crate) fn set_notify(&self, notify: &Notify) {
+        let mut current = self.notify
+            .lock()
+            .expect("notify lock poisoned");
+        if current.is_none() {
+            *current = Some(notify.clone());
+        }
+    }
+
+    /// Drop the output cached for the inode, as the node was relinked or removed.
+    fn discard_output(&self, inode: u64) {
+        self.cache()
+            .invalidate(inode);
+    }
+
+    pub(crate) fn cache(&self) -> MutexGuard<'_, FiltrateCache> {
+        self.cache
+            .lock()
+            .expect("cache lock poisoned")
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
+    use futures_util::future::BoxFuture;
+    use std::{
+        ffi::OsString,
+        time::SystemTime,
+    };
+    use tokio::sync::oneshot;
+
+    use crate::{
+        conflict::Conflict,
+        effect::Mirror,
+        entry::Attr,
+        error::EffectError,
+        filter::{
+            Filter,
+            PreciseFilter,
+        },
+        future::{
+            FileSize,
+            Filtrate,
+            Listing as LazyListing,
+        },
+        source::Source,
+        traits::{
+            AsyncEffect,
+            Effect,
+            EffsSource,
+        },
+    };
+    use std::sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    };
     use super::*;
 
+    /// Provides a fixed listing of files at the root of the request.
+    struct Listing(&'static [&'static str]);
+
+    impl Effect for Listing {
+        fn apply(&mut self, _: &Path, request: &Path) -> Result<Vec<(OsString, Entry)>, EffectError> {
+            if request != Path::new("") {
+                return Err(EffectError::BadRequestPath(request.into(), "not a directory"))
+            }
+            Ok(self.0.iter()
+                .map(|name| (name.into(), Bytes::from_static(name.as_bytes()).into()))
+                .collect())
+        }
+    }
+
+    /// Provides a listing only after being signalled.
+    struct Pending(Option<oneshot::Receiver<()>>);
+
+    impl AsyncEffect for Pending {
+        fn apply<'a>(
+            &'a mut self,
+            _: &'a Path,
+            _: &'a Path,
+        ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+            Box::pin(async move {
+                if let Some(signal) = self.0.take() {
+                    signal.await.ok();
+                }
+                Ok(vec![("late".into(), Bytes::from_static(b"late").into())])
+            })
+        }
+    }
+
+    /// Counts the number of times it was asked for a listing.
+    struct Counting(Arc<AtomicUsize>, &'static str);
+
+    impl EffsSource for Counting {
+        fn dir(&mut self, _: &Path) -> Result<Vec<(OsString, Entry)>, SourceError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![(self.1.into(), Bytes::from_static(b"").into())])
+        }
+    }
+
+    async fn names(fs: &Effs, path: &str) -> anyhow::Result<Vec<OsString>> {
+        let node_id = fs.path_to_node_id(Path::new(path)).await?;
+        let nodes = fs.nodes.read().await;
+        Ok(node_id.children(&nodes.0)
+            .map(|nid| nodes[nid].name.clone())
+            .collect())
+    }
+
     #[tokio::test]
     async fn basic() -> anyhow::Result<()> {
         let fs = Effs::default();
@@ -114,4 +834,481 @@ mod tests {
         assert!(fs.path_to_node_id(Path::new("/no_such_path")).await.is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn dest_path() -> anyhow::Result<()> {
+        let fs = Effs::default();
+        fs.push_source(Source::new("".into(), "/photos".into(), Listing(&["a.jpg", "b.jpg"]))).await?;
+        fs.push_source(Source::new("".into(), "docs/text".into(), Listing(&["a.txt"]))).await?;
+        fs.push_source(Source::new("".into(), "".into(), Listing(&["README"]))).await?;
+
+        fs.build_nodes(Path::new("/")).await?;
+        assert_eq!(names(&fs, "/").await?, ["photos", "docs", "README"]);
+
+        fs.build_nodes(Path::new("/photos")).await?;
+        assert_eq!(names(&fs, "/photos").await?, ["a.jpg", "b.jpg"]);
+
+        fs.build_nodes(Path::new("/docs")).await?;
+        assert_eq!(names(&fs, "/docs").await?, ["text"]);
+        fs.build_nodes(Path::new("/docs/text")).await?;
+        assert_eq!(names(&fs, "/docs/text").await?, ["a.txt"]);
+
+        // rebuilding the root must not lose the already listed subdirectories
+        fs.build_nodes(Path::new("/")).await?;
+        assert_eq!(names(&fs, "/").await?, ["photos", "docs", "README"]);
+        assert!(fs.path_to_node_id(Path::new("/photos/a.jpg")).await.is_ok());
+        assert!(fs.path_to_node_id(Path::new("/docs/text/a.txt")).await.is_ok());
+        assert!(fs.path_to_node_id(Path::new("/photos/a.txt")).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conflict() -> anyhow::Result<()> {
+        use std::collections::BTreeSet;
+
+        let roots = [tempfile::tempdir()?, tempfile::tempdir()?];
+        for (root, name) in roots.iter().zip(["x", "y"]) {
+            std::fs::write(root.path().join("file.txt"), name)?;
+            std::fs::create_dir(root.path().join("dir"))?;
+            std::fs::write(root.path().join("dir").join(name), name)?;
+        }
+        let roots = &roots;
+        let effs = |conflict| async move {
+            let effs = Effs::builder()
+                .conflict(conflict)
+                .build();
+            for (root, label) in roots.iter().zip(["a", "b"]) {
+                effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())
+                    .with_label(label)).await?;
+            }
+            effs.build_nodes(Path::new("/")).await?;
+            anyhow::Ok(effs)
+        };
+        async fn listed(effs: &Effs, path: &str) -> anyhow::Result<BTreeSet<OsString>> {
+            effs.build_nodes(Path::new(path)).await?;
+            Ok(names(effs, path).await?.into_iter().collect())
+        }
+        let set = |names: &[&str]| names.iter()
+            .map(OsString::from)
+            .collect::<BTreeSet<_>>();
+
+        let merged = effs(Conflict::Merge).await?;
+        assert_eq!(listed(&merged, "/").await?, set(&["dir", "file.txt"]));
+        assert_eq!(listed(&merged, "/dir").await?, set(&["x", "y"]));
+        assert_eq!(merged.owner(Path::new("/file.txt")).await?.as_deref(), Some("b"));
+        assert_eq!(merged.owner(Path::new("/dir")).await?.as_deref(), Some("a"));
+
+        let first = effs(Conflict::FirstWins).await?;
+        assert_eq!(listed(&first, "/dir").await?, set(&["x"]));
+        assert_eq!(first.owner(Path::new("/file.txt")).await?.as_deref(), Some("a"));
+
+        let last = effs(Conflict::LastWins).await?;
+        assert_eq!(listed(&last, "/dir").await?, set(&["y"]));
+        assert_eq!(last.owner(Path::new("/dir/y")).await?.as_deref(), Some("b"));
+
+        let suffixed = effs(Conflict::Suffix).await?;
+        assert_eq!(
+            listed(&suffixed, "/").await?,
+            set(&["dir", "dir~b", "file.txt", "file~b.txt"]),
+        );
+        assert_eq!(listed(&suffixed, "/dir").await?, set(&["x"]));
+        // the renamed directory is still requested from its source by its original name
+        assert_eq!(listed(&suffixed, "/dir~b").await?, set(&["y"]));
+        assert_eq!(suffixed.owner(Path::new("/dir~b/y")).await?.as_deref(), Some("b"));
+        assert_eq!(suffixed.owner(Path::new("/")).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rebuild_removes_stale() -> anyhow::Result<()> {
+        use std::collections::BTreeSet;
+
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("deleted"), b"")?;
+        std::fs::write(root.path().join("renamed"), b"")?;
+        std::fs::write(root.path().join("file"), b"")?;
+        std::fs::create_dir(root.path().join("dir"))?;
+        std::fs::write(root.path().join("dir").join("child"), b"")?;
+
+        let effs = Effs::default();
+        effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+        effs.build_nodes(Path::new("/")).await?;
+        effs.build_nodes(Path::new("/dir")).await?;
+        let before = {
+            let nodes = effs.nodes.read().await;
+            nodes.0.iter()
+                .filter(|node| !node.is_removed())
+                .map(|node| {
+                    let node_id = nodes.0.get_node_id(node).expect("node is in arena");
+                    (usize::from(node_id) as u64, node.get().generation)
+                })
+                .collect::<Vec<_>>()
+        };
+        let child = effs.path_to_node_id(Path::new("/dir/child")).await?;
+
+        std::fs::remove_file(root.path().join("deleted"))?;
+        std::fs::rename(root.path().join("renamed"), root.path().join("moved"))?;
+        // swap the types of file and dir
+        std::fs::remove_dir_all(root.path().join("dir"))?;
+        std::fs::remove_file(root.path().join("file"))?;
+        std::fs::write(root.path().join("dir"), b"")?;
+        std::fs::create_dir(root.path().join("file"))?;
+        effs.build_nodes(Path::new("/")).await?;
+
+        assert_eq!(
+            names(&effs, "/").await?.into_iter().collect::<BTreeSet<_>>(),
+            ["dir", "file", "moved"].into_iter().map(OsString::from).collect(),
+        );
+        assert!(effs.path_to_node_id(Path::new("/deleted")).await.is_err());
+        assert!(effs.path_to_node_id(Path::new("/renamed")).await.is_err());
+        assert!(effs.path_to_node_id(Path::new("/dir/child")).await.is_err());
+        {
+            let nodes = effs.nodes.read().await;
+            assert!(child.is_removed(&nodes.0));
+            let dir = nodes.basic_lookup_node_id_name(nodes.node_id(1)?, "dir".as_ref())?;
+            assert!(!matches!(nodes[dir].entry, Some(Entry::Dir(_))));
+            let file = nodes.basic_lookup_node_id_name(nodes.node_id(1)?, "file".as_ref())?;
+            assert!(matches!(nodes[file].entry, Some(Entry::Dir(_))));
+
+            // inodes that are reused will have a newer generation
+            for (inode, generation) in before {
+                if let Ok(node_id) = nodes.basic_node_id(inode) {
+                    assert!(inode == 1 || nodes[node_id].generation > generation);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Provides a nested tree through a single lazy directory at the root of the request.
+    struct Tree(LazyDir);
+
+    impl Tree {
+        fn new(count: Arc<AtomicUsize>) -> Self {
+            Self(LazyDir::new(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+                LazyListing::new(async {
+                    let inner = LazyDir::new(|| LazyListing::new(async {
+                        Ok(vec![("deep.txt".into(), Bytes::from_static(b"deep").into())])
+                    }));
+                    Ok(vec![
+                        ("leaf.txt".into(), Bytes::from_static(b"leaf").into()),
+                        ("inner".into(), inner.into()),
+                    ])
+                })
+            }))
+        }
+    }
+
+    impl Effect for Tree {
+        fn apply(&mut self, _: &Path, request: &Path) -> Result<Vec<(OsString, Entry)>, EffectError> {
+            if request != Path::new("") {
+                return Err(EffectError::BadRequestPath(request.into(), "not a directory"))
+            }
+            Ok(vec![("tree".into(), self.0.clone().into())])
+        }
+    }
+
+    #[tokio::test]
+    async fn lazy_dir() -> anyhow::Result<()> {
+        use fuse3::{
+            FileType,
+            raw::{
+                Filesystem,
+                Request,
+            },
+        };
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let fs = Effs::default();
+        fs.push_source(Source::new("".into(), "".into(), Tree::new(count.clone()))).await?;
+        fs.push_source(Source::new("".into(), "tree".into(), Listing(&["merged"]))).await?;
+
+        fs.build_nodes(Path::new("/")).await?;
+        assert_eq!(names(&fs, "/").await?, ["tree"]);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        for _ in 0..2 {
+            fs.build_nodes(Path::new("/")).await?;
+            fs.build_nodes(Path::new("/tree")).await?;
+            assert_eq!(names(&fs, "/tree").await?, ["merged", "leaf.txt", "inner"]);
+            fs.build_nodes(Path::new("/tree/inner")).await?;
+            assert_eq!(names(&fs, "/tree/inner").await?, ["deep.txt"]);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(fs.owner(Path::new("/tree/inner/deep.txt")).await?.as_deref(), Some("0"));
+
+        // looking up what was never listed produces the lazy directories along the way
+        let fs = Effs::default();
+        fs.push_source(Source::new("".into(), "".into(), Tree::new(count.clone()))).await?;
+        let tree = fs.lookup(Request::default(), 1, "tree".as_ref()).await?;
+        let inner = fs.lookup(Request::default(), tree.attr.ino, "inner".as_ref()).await?;
+        assert_eq!(inner.attr.kind, FileType::Directory);
+        let deep = fs.lookup(Request::default(), inner.attr.ino, "deep.txt".as_ref()).await?;
+        assert_eq!(deep.attr.size, 4);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh() -> anyhow::Result<()> {
+        let policies = [
+            Refresh::Always,
+            Refresh::Ttl(Duration::ZERO),
+            Refresh::Ttl(Duration::from_secs(3600)),
+            Refresh::Once,
+            Refresh::Explicit,
+        ];
+        let fs = Effs::default();
+        let mut counts = Vec::new();
+        for (refresh, name) in policies.into_iter().zip(["a", "b", "c", "d", "e"]) {
+            let count = Arc::new(AtomicUsize::new(0));
+            fs.push_source_with_refresh(Counting(count.clone(), name), refresh).await?;
+            counts.push(count);
+        }
+        let counts = || counts.iter()
+            .map(|count| count.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+        let root = fs.path_to_node_id(Path::new("/")).await?;
+
+        fs.refresh(root).await?;
+        assert_eq!(counts(), [1, 1, 1, 1, 1]);
+        let generation = {
+            let d = fs.path_to_node_id(Path::new("/d")).await?;
+            fs.nodes.read().await[d].generation
+        };
+        fs.refresh(root).await?;
+        fs.refresh(root).await?;
+        assert_eq!(counts(), [3, 3, 1, 1, 1]);
+
+        fs.invalidate(Path::new("/")).await?;
+        fs.refresh(root).await?;
+        assert_eq!(counts(), [4, 4, 2, 1, 2]);
+
+        // the entries from the reused listings are retained as they were
+        assert_eq!(names(&fs, "/").await?, ["a", "b", "c", "d", "e"]);
+        let d = fs.path_to_node_id(Path::new("/d")).await?;
+        assert_eq!(fs.nodes.read().await[d].generation, generation);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listings() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("dir").join("nested"))?;
+
+        let fs = Effs::default();
+        for refresh in [Refresh::Always, Refresh::Explicit] {
+            let source = Source::new(root.path().into(), "".into(), Mirror::default());
+            fs.push_source_with_refresh(source, refresh).await?;
+        }
+        let listed = || async {
+            let sources = fs.sources.read().await.clone();
+            let mut listed = Vec::new();
+            for slot in sources {
+                let mut paths = slot.state.lock().await.listings.keys().cloned().collect::<Vec<_>>();
+                paths.sort();
+                listed.push(paths);
+            }
+            listed
+        };
+        fs.build_nodes(Path::new("/")).await?;
+        fs.build_nodes(Path::new("/dir")).await?;
+        fs.build_nodes(Path::new("/dir/nested")).await?;
+        // the listings are never reused by `Refresh::Always`, so they are not kept
+        assert_eq!(listed().await, [vec![], vec![
+            PathBuf::from(""),
+            PathBuf::from("dir"),
+            PathBuf::from("dir/nested"),
+        ]]);
+
+        // the listings for the removed directories are dropped
+        std::fs::remove_dir_all(root.path().join("dir"))?;
+        fs.invalidate(Path::new("/")).await?;
+        fs.refresh(fs.path_to_node_id(Path::new("/")).await?).await?;
+        assert_eq!(listed().await, [vec![], vec![PathBuf::from("")]]);
+        Ok(())
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn watch() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("dir"))?;
+        std::fs::write(root.path().join("dir").join("file"), b"file")?;
+
+        let effs = Effs::builder()
+            .watch(true)
+            .build();
+        effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+        effs.build_nodes(Path::new("/")).await?;
+        effs.build_nodes(Path::new("/dir")).await?;
+        let dir = effs.path_to_node_id(Path::new("/dir")).await?;
+        let file = effs.path_to_node_id(Path::new("/dir/file")).await?;
+        let generation = effs.nodes.read().await[file].generation;
+
+        std::fs::write(root.path().join("dir").join("file"), b"modified")?;
+        std::fs::write(root.path().join("dir").join("new"), b"new")?;
+        let mut waited = 0;
+        while !effs.nodes.read().await[dir].dirty {
+            assert!(waited < 500, "directory was never marked as dirty");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            waited += 1;
+        }
+        // the directory is only rebuilt when it is next accessed
+        assert_eq!(names(&effs, "/dir").await?, ["file"]);
+
+        effs.refresh(dir).await?;
+        assert_eq!(names(&effs, "/dir").await?, ["file", "new"]);
+        assert!(effs.nodes.read().await[file].generation > generation);
+
+        // the origin of the directory that was removed is no longer watched
+        let watcher = effs.watcher.as_ref().expect("watcher was started");
+        assert_eq!(watcher.watched(), [PathBuf::from(""), PathBuf::from("dir")]);
+        std::fs::rename(root.path().join("dir"), root.path().join("moved"))?;
+        effs.refresh(effs.path_to_node_id(Path::new("/")).await?).await?;
+        assert_eq!(names(&effs, "/").await?, ["moved"]);
+        assert_eq!(watcher.watched(), [PathBuf::from("")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn async_source() -> anyhow::Result<()> {
+        let fs = Effs::default();
+        let (signal, pending) = oneshot::channel();
+        fs.push_source(Source::new("".into(), "".into(), Pending(Some(pending)))).await?;
+        let (built, found) = tokio::join!(
+            fs.build_nodes(Path::new("/")),
+            async {
+                // the nodes remain accessible while the source is producing the listing
+                let found = fs.path_to_node_id(Path::new("/")).await;
+                signal.send(()).ok();
+                found
+            },
+        );
+        built?;
+        found?;
+        assert_eq!(names(&fs, "/").await?, ["late"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_source() -> anyhow::Result<()> {
+        let fs = Effs::default();
+        let (signal, pending) = oneshot::channel();
+        fs.push_source(Source::new("".into(), "slow".into(), Pending(Some(pending)))).await?;
+        fs.push_source(Source::new("".into(), "fast".into(), Listing(&["a"]))).await?;
+        fs.build_nodes(Path::new("/")).await?;
+        let (slow, fast) = tokio::join!(
+            fs.build_nodes(Path::new("/slow")),
+            async {
+                // other directories may be built while the slow source is producing its listing
+                let built = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    fs.build_nodes(Path::new("/fast")),
+                ).await;
+                signal.send(()).ok();
+                built
+            },
+        );
+        slow?;
+        fast??;
+        assert_eq!(names(&fs, "/slow").await?, ["late"]);
+        assert_eq!(names(&fs, "/fast").await?, ["a"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_size() -> anyhow::Result<()> {
+        let fs = Effs::default();
+        {
+            let mut nodes = fs.nodes.write().await;
+            let root = nodes.node_id(1)?;
+            nodes.link_entry(root, "filter".into(), Filter::new(|| {
+                Filtrate::new(async { Ok(Bytes::from_static(b"12345")) })
+            }).into())?;
+            nodes.link_entry(root, "hinted".into(), Filter::new(|| {
+                Filtrate::new(async { unreachable!("size is provided by hint") })
+            }).with_size(|| FileSize::hint(42)).into())?;
+            nodes.link_entry(root, "precise".into(), PreciseFilter::new(|_, _| {
+                Filtrate::new(async { unreachable!("size is not resolvable") })
+            }).into())?;
+        }
+
+        for (path, expected) in [("filter", Some(5)), ("hinted", Some(42)), ("precise", None)] {
+            let node_id = fs.path_to_node_id(Path::new(path)).await?;
+            assert_eq!(fs.nodes.read().await[node_id].size, None);
+            fs.resolve_size(node_id).await?;
+            assert_eq!(fs.nodes.read().await[node_id].size, expected);
+        }
+        Ok(())
+    }
+
+    /// Provides a single filter that was last modified at the time.
+    struct Modified(Arc<Mutex<SystemTime>>);
+
+    impl Effect for Modified {
+        fn apply(&mut self, _: &Path, _: &Path) -> Result<Vec<(OsString, Entry)>, EffectError> {
+            let attr = Attr {
+                mtime: Some(*self.0.lock().expect("mtime lock poisoned")),
+                ..Default::default()
+            };
+            Ok(vec![("file".into(), Filter::new(|| {
+                Filtrate::new(async { Ok(Bytes::from_static(b"12345")) })
+            }).with_attr(attr).into())])
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_invalidation() -> anyhow::Result<()> {
+        let fs = Effs::builder()
+            .cache_size(1024)
+            .build();
+        let mtime = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+        let source = Source::new("".into(), "".into(), Modified(mtime.clone()));
+        fs.push_source_with_refresh(source, Refresh::Always).await?;
+        fs.build_nodes(Path::new("/")).await?;
+        let node_id = fs.path_to_node_id(Path::new("/file")).await?;
+        let inode = usize::from(node_id) as u64;
+        let generation = fs.nodes.read().await[node_id].generation;
+
+        // resolving the size will have produced the output, which is cached
+        fs.resolve_size(node_id).await?;
+        assert_eq!(fs.cache().get(inode, generation), Some(Bytes::from_static(b"12345")));
+
+        // the listing is produced again, but the unchanged entry keeps its cached output
+        fs.build_nodes(Path::new("/")).await?;
+        assert_eq!(fs.nodes.read().await[node_id].generation, generation);
+        assert_eq!(fs.nodes.read().await[node_id].size, Some(5));
+        assert_eq!(fs.cache().get(inode, generation), Some(Bytes::from_static(b"12345")));
+
+        // the modified entry is relinked, which invalidates the cached output
+        *mtime.lock().expect("mtime lock poisoned") += Duration::from_secs(1);
+        fs.build_nodes(Path::new("/")).await?;
+        assert!(fs.nodes.read().await[node_id].generation > generation);
+        assert_eq!(fs.cache().get(inode, generation), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_node() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("dir"))?;
+        std::fs::write(root.path().join("file"), b"file")?;
+
+        let fs = Effs::default();
+        fs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+        fs.build_nodes(Path::new("/")).await?;
+        let dir = fs.path_to_node_id(Path::new("/dir")).await?;
+        let file = fs.path_to_node_id(Path::new("/file")).await?;
+
+        // the nodes are removed while they are held onto, as they are when the lock on the
+        // nodes is released in between
+        std::fs::remove_dir(root.path().join("dir"))?;
+        std::fs::remove_file(root.path().join("file"))?;
+        fs.build_nodes(Path::new("/")).await?;
+        assert!(fs.resolve_size(file).await.is_err());
+        assert!(!fs.stale(dir).await);
+        Ok(())
+    }
 }
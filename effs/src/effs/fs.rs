@@ -1,36 +1,50 @@
+use bytes::Bytes;
 use fuse3::{
     raw::prelude::*,
-    Errno,
     Result,
 };
 use futures_util::{
-    StreamExt,
     stream::{
         self,
         Iter,
     },
 };
+use indextree::NodeId;
 use std::{
     ffi::{
         OsStr,
         OsString,
     },
-    iter::Skip,
     num::NonZeroU32,
+    os::unix::ffi::OsStrExt as _,
     time::Duration,
-    vec::IntoIter,
 };
+use tokio::sync::RwLockReadGuard;
 
-use crate::entry::Entry;
+use crate::{
+    entry::Entry,
+    handle::{
+        DirSnapshot,
+        Handle,
+    },
+    node::{
+        Node,
+        Nodes,
+    },
+};
 use super::Effs;
 
 const TTL: Duration = Duration::from_secs(1);
+/// The TTL while the origins are watched but the kernel cannot yet be told of their changes,
+/// such that the changes are noticed soon after the directories are marked as dirty.
+#[cfg(feature = "watch")]
+const UNNOTIFIED_TTL: Duration = Duration::from_millis(100);
 
 impl Filesystem for Effs {
-    type DirEntryStream<'a> = Iter<IntoIter<Result<DirectoryEntry>>>
+    type DirEntryStream<'a> = Iter<DirEntries<'a, DirectoryEntry>>
     where
         Self: 'a;
-    type DirEntryPlusStream<'a> = Iter<IntoIter<Result<DirectoryEntryPlus>>>
+    type DirEntryPlusStream<'a> = Iter<DirEntries<'a, DirectoryEntryPlus>>
     where
         Self: 'a;
 
@@ -49,18 +63,39 @@ impl Filesystem for Effs {
         name: &OsStr,
     ) -> Result<ReplyEntry> {
         tracing::debug!("lookup parent={parent} name={name:?}");
+        let par_node_id = self.nodes
+            .read()
+            .await
+            .node_id(parent)?;
+        self.refresh_dirty(par_node_id)
+            .await
+            .map_err(|_| libc::EIO)?;
+        let lookup = self.nodes
+            .read()
+            .await
+            .lookup_node_id_name(par_node_id, name);
+        let node_id = match lookup {
+            // The directory may have never been listed, or the name may have been added since
+            // it was last built, so build it before giving up should that be possible.
+            Err(e) if e == libc::ENOENT.into() && self.stale(par_node_id).await => {
+                self.refresh(par_node_id)
+                    .await
+                    .map_err(|_| libc::EIO)?;
+                self.nodes
+                    .read()
+                    .await
+                    .lookup_node_id_name(par_node_id, name)?
+            }
+            result => result?,
+        };
+        self.resolve_node_size(node_id).await;
         let nodes = self.nodes
             .read()
             .await;
-        let (node, attr) = nodes.attr_for_node_id(
-            nodes.lookup_node_id_name(
-                nodes.node_id(parent)?,
-                name,
-            )?
-        )?;
+        let (node, attr) = nodes.attr_for_node_id(node_id)?;
         Ok(ReplyEntry {
-            ttl: TTL,
-            attr: attr,
+            ttl: self.ttl(),
+            attr,
             generation: node.generation,
         })
     }
@@ -74,138 +109,615 @@ impl Filesystem for Effs {
     ) -> Result<ReplyAttr> {
         // let path = path.ok_or_else(Errno::new_not_exist)?.to_string_lossy();
         tracing::debug!("getattr inode={inode:?}");
+        let node_id = self.nodes
+            .read()
+            .await
+            .node_id(inode)?;
+        self.refresh_parent(node_id)
+            .await
+            .map_err(|_| libc::EIO)?;
+        // the parent may have been rebuilt without the node
+        let node_id = self.nodes
+            .read()
+            .await
+            .node_id(inode)?;
+        self.resolve_node_size(node_id).await;
         let nodes = self.nodes
             .read()
             .await;
-        let (_, attr) = nodes.attr_for_inode(inode)?;
+        let (_, attr) = nodes.with_inode(inode, Result::Ok)?;
 
         Ok(ReplyAttr {
-            ttl: TTL,
+            ttl: self.ttl(),
             attr,
         })
     }
 
+    async fn opendir(&self, _req: Request, inode: u64, _flags: u32) -> Result<ReplyOpen> {
+        let snapshot = self.snapshot_dir(inode).await?;
+        let fh = self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .opendir(snapshot);
+        tracing::debug!("opendir inode={inode} fh={fh}");
+        Ok(ReplyOpen { fh, flags: 0 })
+    }
+
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        parent: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'a>>> {
+        let snapshot = self.dir_snapshot(parent, fh).await?;
+        let ttl = self.ttl();
+        let nodes = self.nodes
+            .read()
+            .await;
+        let entries = DirEntries::new(nodes, snapshot, offset as usize, ttl, |offset, _, attr, name, _| {
+            DirectoryEntry {
+                inode: attr.ino,
+                kind: attr.kind,
+                name,
+                offset,
+            }
+        });
+
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
     async fn readdirplus<'a>(
         &'a self,
         _req: Request,
         parent: u64,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         _lock_owner: u64,
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'a>>> {
-        // TODO this block should only run when the configuration to always
-        // refresh when readdirplus is called be enabled.
-        // Currently, this enables the dynamic generation of new listings, but
-        // not exactly in a friendly manner.
-        {
-            let path = self.path_of_inode(parent)
-                // this error implies the parent inode disappeared
-                .await
-                .map_err(|_| libc::ENOENT)?;
-            // TODO log this error?
-            self.build_nodes(&path)
-                .await
-                .map_err(|_| libc::ENOTRECOVERABLE)?;
-        }
+        let snapshot = self.dir_snapshot(parent, fh).await?;
+        let ttl = self.ttl();
         let nodes = self.nodes
             .read()
             .await;
-        let arena = &nodes.0;
-
-        let cur_nid = nodes.node_id(parent)?;
-        let cur_entry = nodes.with_node_id(cur_nid, |(node, attr)| {
-            match attr.kind {
-                FileType::Directory => Ok(DirectoryEntryPlus {
-                    inode: attr.ino,
-                    generation: node.generation,
-                    kind: attr.kind,
-                    name: OsString::from("."),
-                    offset: 1,
-                    attr: attr,
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
-                }),
-                _ => Err(libc::ENOTDIR.into())
-            }
-        })?;
-
-        let par_nid = cur_nid.ancestors(arena)
-            .next()
-            .unwrap_or(cur_nid);
-        let par_entry = nodes.with_node_id(par_nid, |(node, attr)| {
-            match attr.kind {
-                FileType::Directory => Ok(DirectoryEntryPlus {
-                    inode: attr.ino,
-                    generation: node.generation,
-                    kind: attr.kind,
-                    name: OsString::from(".."),
-                    offset: 2,
-                    attr: attr,
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
-                }),
-                _ => Err(libc::ENOTDIR.into())
+        let entries = DirEntries::new(nodes, snapshot, offset as usize, ttl, |offset, node, attr, name, ttl| {
+            DirectoryEntryPlus {
+                inode: attr.ino,
+                generation: node.generation,
+                kind: attr.kind,
+                name,
+                offset,
+                attr,
+                entry_ttl: ttl,
+                // size is not yet resolved, so have the kernel getattr it.
+                attr_ttl: if node.size.is_some() { ttl } else { Duration::ZERO },
             }
-        })?;
-
-        let pre_entries = vec![Ok(cur_entry), Ok(par_entry)].into_iter();
-
-        // TODO use stream::iter to do this in one slurp rather than buffering this
-        let entries = pre_entries
-            .chain(
-                cur_nid.children(arena)
-                    .filter_map(|nid| nodes.attr_for_node_id(nid).ok())
-                    .enumerate()
-                    .map(|(i, (node, attr))| {
-                        Ok(DirectoryEntryPlus {
-                            inode: attr.ino,
-                            generation: node.generation,
-                            kind: attr.kind,
-                            name: node.name.clone(),
-                            offset: i as i64 + 3,
-                            attr: attr,
-                            entry_ttl: TTL,
-                            attr_ttl: TTL,
-                        })
-                    })
-            )
-            .skip(offset as usize)
-            .collect::<Vec<_>>();
+        });
 
         Ok(ReplyDirectoryPlus {
             entries: stream::iter(entries),
         })
     }
 
+    async fn releasedir(&self, _req: Request, inode: u64, fh: u64, _flags: u32) -> Result<()> {
+        tracing::debug!("releasedir inode={inode} fh={fh}");
+        self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .releasedir(fh);
+        Ok(())
+    }
+
+    async fn readlink(&self, _req: Request, inode: u64) -> Result<ReplyData> {
+        tracing::debug!("readlink inode={inode}");
+        self.nodes
+            .read()
+            .await
+            .with_inode(inode, |(node, _)| match &node.entry {
+                Some(Entry::Symlink(target)) => Ok(ReplyData {
+                    data: Bytes::copy_from_slice(target.as_os_str().as_bytes()),
+                }),
+                _ => Err(libc::EINVAL.into()),
+            })
+    }
+
     async fn open(&self, _req: Request, inode: u64, flags: u32) -> Result<ReplyOpen> {
-        let nodes = self.nodes
+        let node_id = self.nodes
             .read()
-            .await;
-        let _ = nodes.node_id(inode)?;
+            .await
+            .node_id(inode)?;
+        self.refresh_parent(node_id)
+            .await
+            .map_err(|_| libc::EIO)?;
+        let (entry, generation) = self.nodes
+            .read()
+            .await
+            .with_inode(inode, |(node, attr)| match (&node.entry, attr.kind) {
+                (_, FileType::Directory) => Err(libc::EISDIR.into()),
+                (Some(entry), _) => Ok((entry.clone(), node.generation)),
+                (None, _) => Err(libc::ENOENT.into()),
+            })?;
+        let cached = self.cache()
+            .get(inode, generation);
+        let fh = self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .open(inode, generation, entry, cached);
+        tracing::debug!("open inode={inode} fh={fh}");
 
         // enable FOPEN_DIRECT_IO
         let flags = flags | 1;
 
-        // TODO set up the future for read to use?
-        Ok(ReplyOpen { fh: 0, flags })
+        Ok(ReplyOpen { fh, flags })
     }
 
     async fn read(
         &self,
         _req: Request,
         inode: u64,
-        // TODO use fh to deal with caching the future that may have been created?
-        _fh: u64,
+        fh: u64,
         offset: u64,
         size: u32,
     ) -> Result<ReplyData> {
+        let handle = self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .get(fh);
+        let handle = match handle {
+            Some(handle) => handle,
+            // the file was not opened through `open`, so read from the node directly
+            None => Handle::new(self.nodes
+                .read()
+                .await
+                .with_inode(inode, |(node, _)| node.entry
+                    .clone()
+                    .ok_or_else(|| libc::ENOENT.into())
+                )?
+            ),
+        };
+        let data = handle.read(offset, size).await?;
+        tracing::debug!("read inode={inode} offset={offset} size={size} got data.len()={}", data.len());
+        Ok(ReplyData { data })
+    }
+
+    async fn release(
+        &self,
+        _req: Request,
+        inode: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        tracing::debug!("release inode={inode} fh={fh}");
+        let released = self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .release(fh);
+        if let Some((generation, handle)) = released {
+            if let Some(filtrate) = handle.filtrate() {
+                self.cache()
+                    .insert(inode, generation, filtrate);
+            }
+        }
+        Ok(())
+    }
+
+    async fn poll(
+        &self,
+        _req: Request,
+        inode: u64,
+        _fh: u64,
+        _kh: Option<u64>,
+        _flags: u32,
+        events: u32,
+        notify: &Notify,
+    ) -> Result<ReplyPoll> {
+        tracing::debug!("poll inode={inode} events={events}");
+        // fuse3 only makes a `Notify` available here, so keep it for invalidating what the
+        // kernel has cached when the origins of the sources are modified.
+        self.set_notify(notify);
+        // the files may always be read from without blocking
+        Ok(ReplyPoll {
+            revents: events & (libc::POLLIN | libc::POLLRDNORM) as u32,
+        })
+    }
+}
+
+impl Effs {
+    /// How long the kernel may cache the entries and their attributes for.
+    fn ttl(&self) -> Duration {
+        #[cfg(feature = "watch")]
+        if self.watcher.is_some() && self.notify.lock().expect("notify lock poisoned").is_none() {
+            return UNNOTIFIED_TTL;
+        }
+        TTL
+    }
+
+    /// Resolve the size of the node, where it remains unknown and is presented as empty should
+    /// the output fail to be produced, as the reads will report the failure instead.
+    async fn resolve_node_size(&self, node_id: NodeId) {
+        if let Err(e) = self.resolve_size(node_id).await {
+            tracing::debug!("failed to resolve the size of inode={}: {e}", usize::from(node_id));
+        }
+    }
+
+    /// Rebuild the directory at the inode should it be stale, and take a snapshot of its entries.
+    async fn snapshot_dir(&self, inode: u64) -> Result<DirSnapshot> {
+        let node_id = {
+            let nodes = self.nodes
+                .read()
+                .await;
+            let node_id = nodes.node_id(inode)?;
+            nodes.with_node_id(node_id, |(_, attr)| match attr.kind {
+                FileType::Directory => Ok(node_id),
+                _ => Err(libc::ENOTDIR.into()),
+            })?
+        };
+        if self.stale(node_id).await {
+            // TODO log this error?
+            self.refresh(node_id)
+                .await
+                .map_err(|_| libc::ENOTRECOVERABLE)?;
+        }
+
         let nodes = self.nodes
             .read()
             .await;
-        let node_id = nodes.node_id(inode)?;
-        let data = nodes.read(node_id, offset, size).await?;
-        tracing::debug!("read inode={inode} offset={offset} size={size} got data.len()={}", data.len());
-        Ok(ReplyData { data: data.into() })
+        if node_id.is_removed(&nodes.0) {
+            return Err(libc::ENOENT.into());
+        }
+        let parent = node_id.parent(&nodes.0)
+            .unwrap_or(node_id);
+        let len = match &nodes[node_id].entry {
+            Some(Entry::Dir(dir)) => dir.children.len(),
+            _ => 0,
+        };
+        let mut snapshot = Vec::with_capacity(len + 2);
+        snapshot.extend([node_id, parent]);
+        snapshot.extend(node_id.children(&nodes.0));
+        Ok(snapshot.into())
+    }
+
+    /// The snapshot of the directory opened as `fh`, or a new snapshot should the directory
+    /// not have been opened through `opendir`.
+    async fn dir_snapshot(&self, inode: u64, fh: u64) -> Result<DirSnapshot> {
+        let snapshot = self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .get_dir(fh);
+        match snapshot {
+            Some(snapshot) => Ok(snapshot),
+            None => self.snapshot_dir(inode).await,
+        }
     }
+}
+
+/// Produces the entries of a directory snapshot from an offset onwards, along with the offset
+/// of the entry that follows each of them, such that the attributes are only computed for the
+/// entries that are consumed.
+///
+/// The lock on the nodes is held until this is dropped, which is fine as fuse3 consumes the
+/// entries as it fills the reply, without awaiting anything else in between.
+pub struct DirEntries<'a, T> {
+    nodes: RwLockReadGuard<'a, Nodes>,
+    snapshot: DirSnapshot,
+    index: usize,
+    ttl: Duration,
+    entry: fn(i64, &Node, FileAttr, OsString, Duration) -> T,
+}
+
+impl<'a, T> DirEntries<'a, T> {
+    fn new(
+        nodes: RwLockReadGuard<'a, Nodes>,
+        snapshot: DirSnapshot,
+        offset: usize,
+        ttl: Duration,
+        entry: fn(i64, &Node, FileAttr, OsString, Duration) -> T,
+    ) -> Self {
+        Self {
+            nodes,
+            snapshot,
+            index: offset,
+            ttl,
+            entry,
+        }
+    }
+}
+
+impl<T> Iterator for DirEntries<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let i = self.index;
+            let nid = *self.snapshot.get(i)?;
+            self.index += 1;
+            // the node may have been removed, or even replaced, since the snapshot was taken
+            if nid.is_removed(&self.nodes.0) {
+                continue;
+            }
+            let Ok((node, attr)) = self.nodes.attr_for_node_id(nid) else {
+                continue;
+            };
+            let name = match i {
+                0 => OsString::from("."),
+                1 => OsString::from(".."),
+                _ => node.name.clone(),
+            };
+            return Some(Ok((self.entry)(i as i64 + 1, node, attr, name, self.ttl)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use fuse3::Errno;
+    use futures_util::{
+        StreamExt,
+        stream,
+    };
+    use std::{
+        collections::BTreeSet,
+        path::Path,
+        sync::{
+            Arc,
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+        },
+    };
+
+    use crate::{
+        Refresh,
+        effect::Mirror,
+        entry::Entry,
+        error::{
+            EffectError,
+            Error,
+        },
+        filter::{
+            Filter,
+            StreamFilter,
+        },
+        future::{
+            Filtrate,
+            FiltrateStream,
+        },
+        source::Source,
+        traits::Effect,
+    };
+    use super::*;
+
+    /// Provides a filter and a stream filter that count the times their outputs were produced,
+    /// and a filter that fails.
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Effect for Counted {
+        fn apply(&mut self, _: &Path, _: &Path) -> std::result::Result<Vec<(OsString, Entry)>, EffectError> {
+            let count = self.0.clone();
+            let stream_count = self.0.clone();
+            Ok(vec![
+                ("counted".into(), Filter::new(move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    Filtrate::new(async { Ok(Bytes::from_static(b"12345")) })
+                }).into()),
+                ("streamed".into(), StreamFilter::new(move || {
+                    stream_count.fetch_add(1, Ordering::SeqCst);
+                    FiltrateStream::new(stream::iter([&b"123"[..], b"45"])
+                        .map(|chunk| Ok(Bytes::from_static(chunk))))
+                }).into()),
+                ("failing".into(), Filter::new(|| {
+                    Filtrate::new(async { Err(Error::Internal) })
+                }).into()),
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn resolved_size() -> anyhow::Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let effs = Effs::builder()
+            .cache_size(1024)
+            .build();
+        effs.push_source(Source::new("".into(), "".into(), Counted(count.clone()))).await?;
+
+        let counted = effs.lookup(Request::default(), 1, "counted".as_ref()).await?;
+        assert_eq!(counted.attr.size, 5);
+        let opened = effs.open(Request::default(), counted.attr.ino, 0).await?;
+        let data = effs.read(Request::default(), counted.attr.ino, opened.fh, 0, 5).await?;
+        assert_eq!(data.data, b"12345".as_ref());
+        effs.release(Request::default(), counted.attr.ino, opened.fh, 0, 0, false).await?;
+        // the output produced to resolve the size was cached, so it was read from the cache
+        assert_eq!(count.load(Ordering::SeqCst), 1);
 
+        // as is the output of a stream drained to find its length
+        let streamed = effs.lookup(Request::default(), 1, "streamed".as_ref()).await?;
+        assert_eq!(streamed.attr.size, 5);
+        let opened = effs.open(Request::default(), streamed.attr.ino, 0).await?;
+        let data = effs.read(Request::default(), streamed.attr.ino, opened.fh, 0, 5).await?;
+        assert_eq!(data.data, b"12345".as_ref());
+        effs.release(Request::default(), streamed.attr.ino, opened.fh, 0, 0, false).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // the size remains unknown, rather than failing the lookup, when the output fails
+        let failing = effs.lookup(Request::default(), 1, "failing".as_ref()).await?;
+        assert_eq!(failing.attr.size, 0);
+        let attr = effs.getattr(Request::default(), failing.attr.ino, None, 0).await?;
+        assert_eq!(attr.attr.size, 0);
+        let opened = effs.open(Request::default(), failing.attr.ino, 0).await?;
+        let read = effs.read(Request::default(), failing.attr.ino, opened.fh, 0, 5).await;
+        assert_eq!(read.err(), Some(Errno::from(libc::EIO)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_unlisted() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("a").join("b"))?;
+        std::fs::write(root.path().join("a").join("b").join("c.txt"), b"hello")?;
+
+        let effs = Effs::default();
+        effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+
+        // none of the directories were listed beforehand
+        let a = effs.lookup(Request::default(), 1, "a".as_ref()).await?;
+        let b = effs.lookup(Request::default(), a.attr.ino, "b".as_ref()).await?;
+        let c = effs.lookup(Request::default(), b.attr.ino, "c.txt".as_ref()).await?;
+        assert_eq!(c.attr.kind, FileType::RegularFile);
+        assert_eq!(c.attr.size, 5);
+        let node_id = effs.path_to_node_id(Path::new("/a/b/c.txt")).await?;
+        assert_eq!(usize::from(node_id) as u64, c.attr.ino);
+
+        let missing = effs.lookup(Request::default(), b.attr.ino, "d.txt".as_ref()).await;
+        assert_eq!(missing.err(), Some(Errno::from(libc::ENOENT)));
+
+        // names added after the directory was built are found
+        std::fs::write(root.path().join("a").join("b").join("d.txt"), b"")?;
+        effs.lookup(Request::default(), b.attr.ino, "d.txt".as_ref()).await?;
+
+        let not_dir = effs.lookup(Request::default(), c.attr.ino, "e.txt".as_ref()).await;
+        assert_eq!(not_dir.err(), Some(Errno::from(libc::ENOTDIR)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_missing() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), b"a")?;
+
+        let effs = Effs::default();
+        let source = Source::new(root.path().into(), "".into(), Mirror::default());
+        effs.push_source_with_refresh(source, Refresh::Explicit).await?;
+        effs.lookup(Request::default(), 1, "a".as_ref()).await?;
+
+        // the listing is reused until the directory is invalidated, so it is not rebuilt
+        std::fs::write(root.path().join("b"), b"b")?;
+        let missing = effs.lookup(Request::default(), 1, "b".as_ref()).await;
+        assert_eq!(missing.err(), Some(Errno::from(libc::ENOENT)));
+
+        effs.invalidate(Path::new("/")).await?;
+        effs.lookup(Request::default(), 1, "b".as_ref()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn getattr_removed() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), b"a")?;
+
+        let effs = Effs::default();
+        let source = Source::new(root.path().into(), "".into(), Mirror::default());
+        effs.push_source_with_refresh(source, Refresh::Explicit).await?;
+        let a = effs.lookup(Request::default(), 1, "a".as_ref()).await?;
+
+        // the parent is rebuilt by getattr, which removes the node it was asked for
+        std::fs::remove_file(root.path().join("a"))?;
+        effs.invalidate(Path::new("/")).await?;
+        let removed = effs.getattr(Request::default(), a.attr.ino, None, 0).await;
+        assert_eq!(removed.err(), Some(Errno::from(libc::ENOENT)));
+        Ok(())
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn watched_ttl() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), b"a")?;
+
+        let effs = Effs::builder()
+            .watch(true)
+            .build();
+        effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+        // the kernel cannot be told of the changes without a `Notify`
+        let a = effs.lookup(Request::default(), 1, "a".as_ref()).await?;
+        assert_eq!(a.ttl, UNNOTIFIED_TTL);
+        let attr = effs.getattr(Request::default(), a.attr.ino, None, 0).await?;
+        assert_eq!(attr.ttl, UNNOTIFIED_TTL);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stable_offsets() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let original = ["a", "b", "c", "d", "e"];
+        for name in original {
+            std::fs::write(root.path().join(name), name)?;
+        }
+
+        let effs = Effs::default();
+        effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+        let dir = effs.opendir(Request::default(), 1, 0).await?;
+        let first = effs.readdirplus(Request::default(), 1, dir.fh, 0, 0).await?
+            .entries
+            .take(4)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(first[0].name, ".");
+        assert_eq!(first[1].name, "..");
+        assert_eq!(first[1].inode, 1);
+
+        // the directory is rebuilt while it is being listed
+        std::fs::remove_file(root.path().join(&first[2].name))?;
+        std::fs::write(root.path().join("new"), b"new")?;
+        let other = effs.opendir(Request::default(), 1, 0).await?;
+
+        let rest = effs.readdirplus(Request::default(), 1, dir.fh, first[3].offset as u64, 0).await?
+            .entries
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let listed = first[2..].iter()
+            .chain(rest.iter())
+            .map(|entry| entry.name.clone())
+            .collect::<Vec<_>>();
+        // nothing was skipped or repeated, and the new entry is not part of this listing
+        assert_eq!(listed.len(), original.len());
+        assert_eq!(
+            listed.into_iter().collect::<BTreeSet<_>>(),
+            original.into_iter().map(OsString::from).collect(),
+        );
+
+        let names = effs.readdir(Request::default(), 1, other.fh, 2).await?
+            .entries
+            .map(|entry| entry.map(|entry| entry.name))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<BTreeSet<_>>>()?;
+        assert!(names.contains(OsStr::new("new")));
+        assert!(!names.contains(&first[2].name));
+
+        effs.releasedir(Request::default(), 1, dir.fh, 0).await?;
+        effs.releasedir(Request::default(), 1, other.fh, 0).await?;
+        assert!(effs.handles.lock().unwrap().get_dir(dir.fh).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readlink() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("data"), b"data")?;
+        std::os::unix::fs::symlink("data", root.path().join("link"))?;
+
+        let effs = Effs::default();
+        effs.push_source(Source::new(root.path().into(), "".into(), Mirror::default())).await?;
+        let link = effs.lookup(Request::default(), 1, "link".as_ref()).await?;
+        assert_eq!(link.attr.kind, FileType::Symlink);
+        assert_eq!(link.attr.size, 4);
+        let target = effs.readlink(Request::default(), link.attr.ino).await?;
+        assert_eq!(target.data, b"data".as_ref());
+
+        let data = effs.lookup(Request::default(), 1, "data".as_ref()).await?;
+        let not_link = effs.readlink(Request::default(), data.attr.ino).await;
+        assert_eq!(not_link.err(), Some(Errno::from(libc::EINVAL)));
+        Ok(())
+    }
 }
@@ -2,19 +2,135 @@ use bytes::Bytes;
 use std::{
     collections::BTreeMap,
     ffi::OsString,
+    fs::Metadata,
+    os::unix::fs::MetadataExt as _,
+    path::PathBuf,
     sync::Arc,
+    time::SystemTime,
 };
+use tokio::sync::OnceCell;
 
-use crate::filter::{
-    Filter,
-    PreciseFilter,
+use crate::{
+    error::Error,
+    filter::{
+        Filter,
+        PreciseFilter,
+        StreamFilter,
+    },
+    future::Listing,
 };
 
-pub type Dir = BTreeMap<OsString, u64>;
+/// The attributes to be presented for an entry.  Any attribute not provided will be filled in
+/// with a default, i.e. the current time for the timestamps and the typical permission bits for
+/// the kind of entry.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attr {
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+    pub ctime: Option<SystemTime>,
+    /// The permission bits.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub nlink: Option<u32>,
+}
+
+impl Attr {
+    /// Only the timestamps of the metadata, for outputs derived from the file it describes, so
+    /// that the output will appear to be modified when the source file is modified.
+    pub fn inherit(metadata: &Metadata) -> Self {
+        Self {
+            atime: metadata.accessed().ok(),
+            mtime: metadata.modified().ok(),
+            ctime: Some(ctime(metadata)),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the attributes describe the same unmodified entry; the access time is ignored
+    /// as reading the entry changes it, and without a modification time there is no telling.
+    pub(crate) fn unmodified(&self, other: &Attr) -> bool {
+        self.mtime.is_some() && Self { atime: other.atime, ..self.clone() } == *other
+    }
+}
+
+impl From<&Metadata> for Attr {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            nlink: Some(metadata.nlink() as u32),
+            ..Self::inherit(metadata)
+        }
+    }
+}
+
+fn ctime(metadata: &Metadata) -> SystemTime {
+    let ctime = std::time::Duration::new(
+        metadata.ctime().max(0) as u64,
+        metadata.ctime_nsec().clamp(0, 999_999_999) as u32,
+    );
+    SystemTime::UNIX_EPOCH + ctime
+}
+
+/// A directory.  Sources need not provide the children, as they will be produced by the
+/// sources when the directory itself is listed.
+#[derive(Clone, Default)]
+pub struct Dir {
+    /// Maps from some name to an inode.
+    pub(crate) children: BTreeMap<OsString, u64>,
+    pub(crate) attr: Attr,
+    /// Produces the children in addition to what the sources provide, for the directories
+    /// that were linked as a `LazyDir`.
+    pub(crate) lazy: Option<LazyDir>,
+}
+
+impl Dir {
+    pub fn with_attr(mut self, attr: Attr) -> Self {
+        self.attr = attr;
+        self
+    }
+}
+
+/// A directory with children that are produced on the first access, such that an effect may
+/// provide a nested tree of entries without handling the requests for the paths within.  The
+/// children may themselves be `LazyDir`s.
+#[derive(Clone)]
+pub struct LazyDir {
+    inner: Arc<dyn Fn() -> Listing + Send + Sync>,
+    listing: Arc<OnceCell<Vec<(OsString, Entry)>>>,
+    pub(crate) attr: Attr,
+}
+
+impl LazyDir {
+    pub fn new(f: impl Fn() -> Listing + Send + Sync + 'static) -> Self {
+        Self {
+            inner: Arc::new(f),
+            listing: Arc::new(OnceCell::new()),
+            attr: Attr::default(),
+        }
+    }
+
+    pub fn with_attr(mut self, attr: Attr) -> Self {
+        self.attr = attr;
+        self
+    }
+
+    /// The children of the directory, produced only once unless an error occurred, along with
+    /// whether they were produced by this call.
+    pub(crate) async fn listing(&self) -> Result<(Vec<(OsString, Entry)>, bool), Error> {
+        let produced = !self.listing.initialized();
+        let listing = self.listing
+            .get_or_try_init(|| (self.inner)())
+            .await?;
+        Ok((listing.clone(), produced))
+    }
+}
 
 #[derive(Clone)]
 pub enum Entry {
-    /// A directory listing.  It maps from some name to an inode.
+    /// A directory listing.
     Dir(Dir),
     /// A standard naive filter, it provides a function that produces a
     /// future that will retrieve the entirety of some output on demand.
@@ -26,6 +142,34 @@ pub enum Entry {
     /// of the output, additional offset and size argument must be
     /// provided to retrieve the desired output.
     PreciseFilter(PreciseFilter),
+    /// A version of filter that provides the output as a stream, such that
+    /// reads may be served while the remainder of the output is still being
+    /// produced.
+    StreamFilter(StreamFilter),
+    /// A symbolic link to the target path.
+    Symlink(PathBuf),
+    /// A directory that produces its own children.
+    LazyDir(LazyDir),
+}
+
+impl Entry {
+    /// The attributes provided for the entry, if the kind of entry is able to carry them.
+    pub fn attr(&self) -> Option<&Attr> {
+        match self {
+            Self::Dir(dir) => Some(&dir.attr),
+            Self::Filter(f) => Some(&f.attr),
+            Self::Filtrated(_) => None,
+            Self::PreciseFilter(f) => Some(&f.attr),
+            Self::StreamFilter(f) => Some(&f.attr),
+            Self::Symlink(_) => None,
+            Self::LazyDir(dir) => Some(&dir.attr),
+        }
+    }
+
+    /// Whether the entry will be presented as a directory.
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Self::Dir(_) | Self::LazyDir(_))
+    }
 }
 
 impl From<Filter> for Entry {
@@ -34,6 +178,30 @@ impl From<Filter> for Entry {
     }
 }
 
+impl From<Dir> for Entry {
+    fn from(dir: Dir) -> Self {
+        Self::Dir(dir)
+    }
+}
+
+impl From<LazyDir> for Entry {
+    fn from(dir: LazyDir) -> Self {
+        Self::LazyDir(dir)
+    }
+}
+
+impl From<PreciseFilter> for Entry {
+    fn from(f: PreciseFilter) -> Self {
+        Self::PreciseFilter(f)
+    }
+}
+
+impl From<StreamFilter> for Entry {
+    fn from(f: StreamFilter) -> Self {
+        Self::StreamFilter(f)
+    }
+}
+
 impl From<Bytes> for Entry {
     fn from(f: Bytes) -> Self {
         Self::Filtrated(f)
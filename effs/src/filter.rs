@@ -1,38 +1,164 @@
 use std::sync::Arc;
 
-use crate::future::Filtrate;
+use crate::{
+    cache::CacheKey,
+    entry::Attr,
+    future::{
+        FileSize,
+        Filtrate,
+        FiltrateStream,
+    },
+};
 
 /// The standard filter, one where the full output will be produced
 #[derive(Clone)]
 pub struct Filter {
     pub(crate) inner: Arc<dyn Fn() -> Filtrate + Send + Sync>,
+    pub(crate) size: Option<Arc<dyn Fn() -> FileSize + Send + Sync>>,
+    pub(crate) attr: Attr,
+    pub(crate) key: Option<CacheKey>,
 }
 
 impl Filter {
     pub fn new(f: impl Fn() -> Filtrate + Send + Sync + 'static) -> Self {
-        Self { inner: Arc::new(f) }
+        Self { inner: Arc::new(f), size: None, key: None, attr: Attr::default() }
+    }
+
+    /// Provide a function that produces a future that will resolve to the size of the
+    /// output, such that the size can be reported without producing the output.  If this
+    /// is not provided, the size will be determined by producing the output.
+    pub fn with_size(mut self, f: impl Fn() -> FileSize + Send + Sync + 'static) -> Self {
+        self.size = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_attr(mut self, attr: Attr) -> Self {
+        self.attr = attr;
+        self
+    }
+
+    /// Provide the key that identifies the output, such that it may be persisted by the
+    /// `DiskCache` if one is configured.
+    pub fn with_key(mut self, key: CacheKey) -> Self {
+        self.key = Some(key);
+        self
     }
 
     // TODO this should be pub(crate)
     pub fn filtrate(&self) -> Filtrate {
         (self.inner)()
     }
+
+    pub(crate) fn file_size(&self) -> Option<FileSize> {
+        self.size.as_ref().map(|f| f())
+    }
 }
 
+type PreciseRead = Arc<dyn Fn(u64, u32) -> Filtrate + Send + Sync>;
+
 /// A version of filter that allows the offset and size be passed and is smart enough
 /// to handle them to return the specific requested slice.
 #[derive(Clone)]
 pub struct PreciseFilter {
-    pub(crate) inner: Arc<dyn Fn(u64, u32) -> Filtrate + Send + Sync>,
+    pub(crate) inner: PreciseRead,
+    pub(crate) size: Option<Arc<dyn Fn() -> FileSize + Send + Sync>>,
+    pub(crate) open: Option<Arc<dyn Fn() -> PreciseRead + Send + Sync>>,
+    pub(crate) attr: Attr,
 }
 
 impl PreciseFilter {
     pub fn new(f: impl Fn(u64, u32) -> Filtrate + Send + Sync + 'static) -> Self {
-        Self { inner: Arc::new(f) }
+        Self { inner: Arc::new(f), size: None, open: None, attr: Attr::default() }
+    }
+
+    /// Provide a function that produces a future that will resolve to the size of the
+    /// output.  Without this the size of the output cannot be known, as a precise filter
+    /// will not produce the entire output.
+    pub fn with_size(mut self, f: impl Fn() -> FileSize + Send + Sync + 'static) -> Self {
+        self.size = Some(Arc::new(f));
+        self
+    }
+
+    /// Provide a function that produces the reads for a handle that is opened for the
+    /// filter, which are used until the handle is released, such that state may be kept
+    /// between the reads through the same handle, e.g. the file being read.  Without this
+    /// every read goes through the function given to `new`.
+    pub fn with_open<F>(mut self, f: impl Fn() -> F + Send + Sync + 'static) -> Self
+    where
+        F: Fn(u64, u32) -> Filtrate + Send + Sync + 'static,
+    {
+        self.open = Some(Arc::new(move || Arc::new(f())));
+        self
+    }
+
+    pub fn with_attr(mut self, attr: Attr) -> Self {
+        self.attr = attr;
+        self
     }
 
     // TODO this should be pub(crate)
     pub fn filtrate(&self, offset: u64, size: u32) -> Filtrate {
         (self.inner)(offset, size)
     }
+
+    /// The filter to read through for a newly opened handle.
+    pub(crate) fn open(&self) -> Self {
+        match &self.open {
+            Some(open) => Self { inner: open(), ..self.clone() },
+            None => self.clone(),
+        }
+    }
+
+    pub(crate) fn file_size(&self) -> Option<FileSize> {
+        self.size.as_ref().map(|f| f())
+    }
+}
+
+/// A version of filter that produces the output as a stream, such that the output may be
+/// served as it is being produced rather than only once the entirety of it is available.
+#[derive(Clone)]
+pub struct StreamFilter {
+    pub(crate) inner: Arc<dyn Fn() -> FiltrateStream + Send + Sync>,
+    pub(crate) size: Option<Arc<dyn Fn() -> FileSize + Send + Sync>>,
+    pub(crate) attr: Attr,
+}
+
+impl StreamFilter {
+    pub fn new(f: impl Fn() -> FiltrateStream + Send + Sync + 'static) -> Self {
+        Self { inner: Arc::new(f), size: None, attr: Attr::default() }
+    }
+
+    /// Provide a function that produces a future that will resolve to the size of the
+    /// output.  If this is not provided, the length of the stream will be used if it is
+    /// known, otherwise the size will be determined by producing the output.
+    pub fn with_size(mut self, f: impl Fn() -> FileSize + Send + Sync + 'static) -> Self {
+        self.size = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_attr(mut self, attr: Attr) -> Self {
+        self.attr = attr;
+        self
+    }
+
+    // TODO this should be pub(crate)
+    pub fn filtrate(&self) -> FiltrateStream {
+        (self.inner)()
+    }
+
+    pub(crate) fn file_size(&self) -> Option<FileSize> {
+        self.size.as_ref().map(|f| f())
+    }
+}
+
+impl From<Filter> for StreamFilter {
+    fn from(f: Filter) -> Self {
+        let size = f.size.clone();
+        let attr = f.attr.clone();
+        Self {
+            inner: Arc::new(move || f.filtrate().into()),
+            size,
+            attr,
+        }
+    }
 }
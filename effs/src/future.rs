@@ -1,5 +1,9 @@
 mod file_size;
 mod filtrate;
+mod filtrate_stream;
+mod listing;
 
 pub use file_size::FileSize;
 pub use filtrate::Filtrate;
+pub use filtrate_stream::FiltrateStream;
+pub use listing::Listing;
@@ -1,6 +1,9 @@
 use pin_project_lite::pin_project;
 use std::{
-    future::Future,
+    future::{
+        self,
+        Future,
+    },
     pin::Pin,
     task::{
         Context,
@@ -21,6 +24,11 @@ impl FileSize {
     pub fn new(fut: impl Future<Output = Result<u64, Error>> + Send + 'static) -> Self {
         Self { inner: Box::pin(fut) }
     }
+
+    /// A file size that is already known.
+    pub fn hint(size: u64) -> Self {
+        Self::new(future::ready(Ok(size)))
+    }
 }
 
 impl Future for FileSize {
@@ -0,0 +1,56 @@
+use bytes::Bytes;
+use futures_util::stream::{
+    self,
+    Stream,
+};
+use pin_project_lite::pin_project;
+use std::{
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use crate::error::Error;
+use super::Filtrate;
+
+pin_project! {
+    /// The output of a filter produced incrementally as a stream of chunks.
+    pub struct FiltrateStream {
+        #[pin]
+        pub(crate) inner: Pin<Box<dyn Stream<Item = Result<Bytes, Error>> + Send>>,
+        pub(crate) len: Option<u64>,
+    }
+}
+
+impl FiltrateStream {
+    pub fn new(stream: impl Stream<Item = Result<Bytes, Error>> + Send + 'static) -> Self {
+        Self { inner: Box::pin(stream), len: None }
+    }
+
+    /// Provide the total length of the output, if it is known ahead of time.
+    pub fn with_len(mut self, len: u64) -> Self {
+        self.len = Some(len);
+        self
+    }
+
+    pub fn known_len(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+impl From<Filtrate> for FiltrateStream {
+    fn from(filtrate: Filtrate) -> Self {
+        Self::new(stream::once(filtrate))
+    }
+}
+
+impl Stream for FiltrateStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        this.inner.poll_next(cx)
+    }
+}
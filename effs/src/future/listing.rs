@@ -0,0 +1,40 @@
+use pin_project_lite::pin_project;
+use std::{
+    ffi::OsString,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use crate::{
+    entry::Entry,
+    error::Error,
+};
+
+pin_project! {
+    /// The children produced for a `LazyDir`.
+    pub struct Listing {
+        #[pin]
+        pub(crate) inner: Pin<Box<dyn Future<Output = Result<Vec<(OsString, Entry)>, Error>> + Send>>,
+    }
+}
+
+impl Listing {
+    pub fn new(
+        fut: impl Future<Output = Result<Vec<(OsString, Entry)>, Error>> + Send + 'static,
+    ) -> Self {
+        Self { inner: Box::pin(fut) }
+    }
+}
+
+impl Future for Listing {
+    type Output = Result<Vec<(OsString, Entry)>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        this.inner.poll(cx)
+    }
+}
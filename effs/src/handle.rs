@@ -0,0 +1,455 @@
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+use fuse3::{
+    Errno,
+    Result,
+};
+use futures_util::{
+    FutureExt,
+    StreamExt,
+    stream::Peekable,
+};
+use indextree::NodeId;
+use std::{
+    cmp::min,
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        Arc,
+        Weak,
+    },
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    entry::Entry,
+    error::Error,
+    future::FiltrateStream,
+};
+
+/// The output of a filter, produced incrementally at most once and shared by all handles that
+/// were opened for the same node.
+pub(crate) type SharedFiltrate = Arc<StreamBuffer>;
+
+/// The entries of a directory at the time it was opened, starting with "." and ".."; the offsets
+/// of the entries are their positions within the snapshot, so they remain stable while the
+/// directory is rebuilt as it is being listed.  The `NodeId`s carry the stamp of the slot they
+/// were allocated at, so the nodes removed since, even if their slots were reused, are known.
+pub(crate) type DirSnapshot = Arc<[NodeId]>;
+
+/// Buffers the output of a filter as it is being streamed, only consuming the stream as far as
+/// required to fulfill the reads.  The reads of what was already produced never wait for the
+/// stream, as only the reads that require more than that take the lock on the stream.
+#[derive(Default)]
+pub(crate) struct StreamBuffer {
+    state: std::sync::Mutex<BufferState>,
+    // the stream will be started on the first read that requires it
+    stream: Mutex<Option<Peekable<FiltrateStream>>>,
+}
+
+#[derive(Default)]
+struct BufferState {
+    // only the chunks that are not empty, along with the offsets they start at, such that the
+    // chunks for a range are found by a binary search
+    chunks: Vec<Bytes>,
+    offsets: Vec<u64>,
+    len: u64,
+    complete: bool,
+    failed: bool,
+}
+
+impl StreamBuffer {
+    /// A buffer with the output already completely produced.
+    pub(crate) fn with_output(output: Bytes) -> Self {
+        let mut state = BufferState::default();
+        state.extend(output);
+        state.complete = true;
+        Self {
+            state: std::sync::Mutex::new(state),
+            stream: Mutex::default(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BufferState> {
+        self.state
+            .lock()
+            .expect("stream buffer lock poisoned")
+    }
+
+    async fn read(
+        &self,
+        start: impl FnOnce() -> FiltrateStream,
+        offset: u64,
+        size: u32,
+    ) -> Result<Bytes> {
+        let end = offset.saturating_add(size as u64);
+        if let Some(output) = self.state().available(offset, end)? {
+            return Ok(output);
+        }
+        let mut stream = self.stream.lock().await;
+        let mut start = Some(start);
+        loop {
+            // another read may have produced the range while this one waited for the stream
+            if let Some(output) = self.state().available(offset, end)? {
+                return Ok(output);
+            }
+            let next = stream
+                .get_or_insert_with(|| start.take().expect("stream is only started once")().peekable())
+                .next()
+                .await;
+            if let Err(e) = self.state().push(next) {
+                *stream = None;
+                return Err(e);
+            }
+            // check whether the stream has ended without waiting, such that the completion may
+            // be noticed without requiring a read past the end.
+            let peeked = stream.as_mut()
+                .map(|stream| Pin::new(stream).peek().now_or_never());
+            if let Some(Some(None)) = peeked {
+                self.state().push(None)?;
+                *stream = None;
+            }
+        }
+    }
+
+    /// The complete output, if it has been produced.
+    fn output(&self) -> Option<Bytes> {
+        let mut state = self.state();
+        if !state.complete {
+            return None;
+        }
+        let output = state.slice(0, state.len);
+        // keep the joined output to avoid joining it again
+        state.chunks.clear();
+        state.offsets.clear();
+        state.len = 0;
+        state.extend(output.clone());
+        Some(output)
+    }
+}
+
+impl BufferState {
+    /// The range of the output, should it have been produced, or as much of it as there is
+    /// should the output be complete.
+    fn available(&self, start: u64, end: u64) -> Result<Option<Bytes>> {
+        if self.failed {
+            return Err(Errno::from(libc::EIO));
+        }
+        Ok((self.complete || end <= self.len).then(|| self.slice(start, end)))
+    }
+
+    fn push(&mut self, next: Option<std::result::Result<Bytes, Error>>) -> Result<()> {
+        match next {
+            Some(Ok(chunk)) => self.extend(chunk),
+            Some(Err(_)) => {
+                self.failed = true;
+                return Err(Errno::from(libc::EIO));
+            }
+            None => self.complete = true,
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, chunk: Bytes) {
+        if !chunk.is_empty() {
+            self.offsets.push(self.len);
+            self.len += chunk.len() as u64;
+            self.chunks.push(chunk);
+        }
+    }
+
+    fn slice(&self, start: u64, end: u64) -> Bytes {
+        let start = min(self.len, start);
+        let end = min(self.len, end);
+        if start >= end {
+            return Bytes::new();
+        }
+        // the last chunk that starts at or before the start of the range is the one it is in
+        let first = self.offsets.partition_point(|offset| *offset <= start) - 1;
+        let (chunk, chunk_start) = (&self.chunks[first], self.offsets[first]);
+        if end <= chunk_start + chunk.len() as u64 {
+            // the range is fully within this chunk, so no copying is required
+            return chunk.slice((start - chunk_start) as usize..(end - chunk_start) as usize);
+        }
+        let mut output = BytesMut::with_capacity((end - start) as usize);
+        for (chunk, chunk_start) in self.chunks[first..].iter().zip(&self.offsets[first..]) {
+            if *chunk_start >= end {
+                break;
+            }
+            let chunk_end = min(end, chunk_start + chunk.len() as u64);
+            output.extend_from_slice(&chunk[start.saturating_sub(*chunk_start) as usize..(chunk_end - chunk_start) as usize]);
+        }
+        output.freeze()
+    }
+}
+
+/// An open file; the entry is kept alongside the filtrate so the node being relinked while the
+/// file is open will not affect what is being read.  A precise filter is opened along with the
+/// handle, so any state it keeps between reads lasts as long as the handle.
+#[derive(Clone)]
+pub(crate) struct Handle {
+    entry: Entry,
+    filtrate: SharedFiltrate,
+}
+
+impl Handle {
+    pub(crate) fn new(entry: Entry) -> Self {
+        Self::with_filtrate(entry, Default::default())
+    }
+
+    pub(crate) fn with_filtrate(entry: Entry, filtrate: SharedFiltrate) -> Self {
+        let entry = match entry {
+            Entry::PreciseFilter(f) => Entry::PreciseFilter(f.open()),
+            entry => entry,
+        };
+        Self { entry, filtrate }
+    }
+
+    /// The output of the filter, if it has been completely produced.
+    pub(crate) fn filtrate(&self) -> Option<Bytes> {
+        match &self.entry {
+            Entry::Filter(_) | Entry::StreamFilter(_) => self.filtrate.output(),
+            _ => None,
+        }
+    }
+
+    pub(crate) async fn read(&self, offset: u64, size: u32) -> Result<Bytes> {
+        match &self.entry {
+            Entry::Dir(_) | Entry::LazyDir(_) => Err(Errno::from(libc::EISDIR)),
+            Entry::Filter(f) => self.filtrate
+                .read(|| f.filtrate().into(), offset, size)
+                .await,
+            Entry::Filtrated(r) => Ok(slice(r, offset, size)),
+            Entry::PreciseFilter(f) => Ok(f.filtrate(offset, size)
+                .await
+                .map_err(|_| Errno::from(libc::EIO))?),
+            Entry::StreamFilter(f) => self.filtrate
+                .read(|| f.filtrate(), offset, size)
+                .await,
+            // the kernel resolves symlinks through `readlink` rather than reading them
+            Entry::Symlink(_) => Err(Errno::from(libc::EINVAL)),
+        }
+    }
+}
+
+fn slice(r: &Bytes, offset: u64, size: u32) -> Bytes {
+    let start = min(r.len(), offset as usize);
+    r.slice(start..min(r.len(), (size as u64 + offset) as usize))
+}
+
+/// Tracks the handles of the currently opened files.
+pub(crate) struct Handles {
+    next_fh: u64,
+    // maps from the fh to the generation of the node the handle was opened for
+    handles: HashMap<u64, (u64, Handle)>,
+    // keyed by inode and generation, so a relinked node will not share the previous output
+    filtrates: HashMap<(u64, u64), Weak<StreamBuffer>>,
+    dirs: HashMap<u64, DirSnapshot>,
+}
+
+impl Default for Handles {
+    fn default() -> Self {
+        Self {
+            // fh 0 is reserved to denote a file that was not opened through `Handles::open`
+            next_fh: 1,
+            handles: HashMap::new(),
+            filtrates: HashMap::new(),
+            dirs: HashMap::new(),
+        }
+    }
+}
+
+impl Handles {
+    /// Allocate a new handle for the entry at the inode, sharing the filtrate with any other
+    /// handles that are currently open for the same inode and generation.  If there are none,
+    /// the `cached` output, if any, will be used as the filtrate.
+    pub(crate) fn open(
+        &mut self,
+        inode: u64,
+        generation: u64,
+        entry: Entry,
+        cached: Option<Bytes>,
+    ) -> u64 {
+        let filtrate = self.filtrates
+            .get(&(inode, generation))
+            .and_then(Weak::upgrade)
+            .unwrap_or_else(|| {
+                let filtrate = Arc::new(match cached {
+                    Some(output) => StreamBuffer::with_output(output),
+                    None => StreamBuffer::default(),
+                });
+                self.filtrates.insert((inode, generation), Arc::downgrade(&filtrate));
+                filtrate
+            });
+        let fh = self.next_fh();
+        self.handles.insert(fh, (generation, Handle::with_filtrate(entry, filtrate)));
+        fh
+    }
+
+    fn next_fh(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    pub(crate) fn get(&self, fh: u64) -> Option<Handle> {
+        self.handles
+            .get(&fh)
+            .map(|(_, handle)| handle.clone())
+    }
+
+    /// Release the handle, returning it along with the generation it was opened for.
+    pub(crate) fn release(&mut self, fh: u64) -> Option<(u64, Handle)> {
+        let handle = self.handles.remove(&fh);
+        self.filtrates.retain(|_, filtrate| filtrate.strong_count() > 0);
+        handle
+    }
+
+    pub(crate) fn opendir(&mut self, snapshot: DirSnapshot) -> u64 {
+        let fh = self.next_fh();
+        self.dirs.insert(fh, snapshot);
+        fh
+    }
+
+    pub(crate) fn get_dir(&self, fh: u64) -> Option<DirSnapshot> {
+        self.dirs
+            .get(&fh)
+            .cloned()
+    }
+
+    pub(crate) fn releasedir(&mut self, fh: u64) -> Option<DirSnapshot> {
+        self.dirs.remove(&fh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    };
+
+    use futures_util::stream;
+
+    use crate::{
+        filter::{
+            Filter,
+            StreamFilter,
+        },
+        future::Filtrate,
+    };
+    use super::*;
+
+    #[tokio::test]
+    async fn shared_filtrate() -> anyhow::Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let entry: Entry = Filter::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Filtrate::new(async { Ok(Bytes::from_static(b"0123456789")) })
+        }).into();
+
+        let mut handles = Handles::default();
+        let fh1 = handles.open(2, 1, entry.clone(), None);
+        let fh2 = handles.open(2, 1, entry.clone(), None);
+        assert_ne!(fh1, fh2);
+        let h1 = handles.get(fh1).expect("handle is open");
+        let h2 = handles.get(fh2).expect("handle is open");
+        let (r1, r2) = tokio::join!(h1.read(0, 4), h2.read(4, 4));
+        assert_eq!(r1?, b"0123".as_ref());
+        assert_eq!(r2?, b"4567".as_ref());
+        assert_eq!(h1.read(8, 4).await?, b"89".as_ref());
+        assert_eq!(h1.read(12, 4).await?, b"".as_ref());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // a different generation will not share the output
+        let fh3 = handles.open(2, 2, entry.clone(), None);
+        handles.get(fh3).expect("handle is open").read(0, 4).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // once all handles are released the output is dropped
+        drop((h1, h2));
+        handles.release(fh1);
+        handles.release(fh2);
+        assert!(handles.get(fh1).is_none());
+        let fh4 = handles.open(2, 1, entry.clone(), None);
+        let h4 = handles.get(fh4).expect("handle is open");
+        assert_eq!(h4.filtrate(), None);
+        h4.read(0, 4).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!(h4.filtrate(), Some(Bytes::from_static(b"0123456789")));
+
+        // a provided cached output will be used rather than producing it again
+        let fh5 = handles.open(3, 1, entry, Some(Bytes::from_static(b"cached")));
+        let h5 = handles.get(fh5).expect("handle is open");
+        assert_eq!(h5.read(0, 4).await?, b"cach".as_ref());
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_filtrate() -> anyhow::Result<()> {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let entry: Entry = StreamFilter::new(move || {
+            let counter = counter.clone();
+            // the stream never completes, as the effect is still producing the output
+            FiltrateStream::new(
+                stream::iter([&b"01"[..], b"23456", b"789"])
+                    .map(move |chunk| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        Ok(Bytes::from_static(chunk))
+                    })
+                    .chain(stream::pending())
+            )
+        }).into();
+
+        let mut handles = Handles::default();
+        let fh = handles.open(2, 1, entry, None);
+        let handle = handles.get(fh).expect("handle is open");
+        // at most a single chunk beyond the requested range is consumed from the stream
+        assert_eq!(handle.read(0, 1).await?, b"0".as_ref());
+        assert_eq!(pulled.load(Ordering::SeqCst), 2);
+        assert_eq!(handle.read(1, 2).await?, b"12".as_ref());
+        assert_eq!(pulled.load(Ordering::SeqCst), 3);
+        assert_eq!(handle.read(3, 2).await?, b"34".as_ref());
+        assert_eq!(pulled.load(Ordering::SeqCst), 3);
+        assert_eq!(handle.read(5, 5).await?, b"56789".as_ref());
+        assert_eq!(pulled.load(Ordering::SeqCst), 3);
+        assert_eq!(handle.filtrate(), None);
+
+        // a read past what was produced waits for the stream, yet the reads of what was
+        // already produced do not wait behind it
+        let mut waiting = std::pin::pin!(handle.read(8, 4));
+        assert!(waiting.as_mut().now_or_never().is_none());
+        let read = handle.read(2, 7)
+            .now_or_never()
+            .expect("the produced output is read without waiting for the stream");
+        assert_eq!(read?, b"2345678".as_ref());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_complete() -> anyhow::Result<()> {
+        let entry: Entry = StreamFilter::new(|| FiltrateStream::new(
+            stream::iter([&b"01"[..], b"", b"234"])
+                .map(|chunk| Ok(Bytes::from_static(chunk)))
+        )).into();
+        let handle = Handle::new(entry);
+        assert_eq!(handle.read(1, 10).await?, b"1234".as_ref());
+        assert_eq!(handle.read(10, 10).await?, b"".as_ref());
+        assert_eq!(handle.filtrate(), Some(Bytes::from_static(b"01234")));
+
+        let entry: Entry = StreamFilter::new(|| FiltrateStream::new(
+            stream::iter([Ok(Bytes::from_static(b"01")), Err(crate::error::Error::Internal)])
+        )).into();
+        let handle = Handle::new(entry);
+        assert_eq!(handle.read(0, 2).await?, b"01".as_ref());
+        assert!(handle.read(0, 4).await.is_err());
+        assert!(handle.read(0, 2).await.is_err());
+        assert_eq!(handle.filtrate(), None);
+        Ok(())
+    }
+}
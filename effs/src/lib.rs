@@ -1,11 +1,20 @@
+pub mod cache;
+pub mod conflict;
 pub mod effect;
 pub mod effs;
 pub mod entry;
 pub mod error;
 pub mod filter;
 pub mod future;
+mod handle;
 pub mod node;
 pub mod source;
 pub mod traits;
+#[cfg(feature = "watch")]
+mod watch;
 
-pub use effs::Effs;
+pub use effs::{
+    Effs,
+    EffsBuilder,
+    Refresh,
+};
@@ -9,12 +9,19 @@ use std::{
         OsStr,
         OsString,
     },
-    collections::BTreeMap,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     ops::{
         Index,
         IndexMut,
     },
-    path::PathBuf,
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
     time::SystemTime,
 };
 
@@ -24,6 +31,7 @@ use crate::{
         Entry,
     },
     error::{
+        Error,
         NoSuchNode,
         NodeLookupError,
     },
@@ -38,35 +46,97 @@ pub struct Node {
     pub(crate) generation: u64,
 
     pub(crate) size: Option<u64>,
-    pub(crate) time: Timestamp,
+    pub(crate) atime: Timestamp,
+    pub(crate) mtime: Timestamp,
+    pub(crate) ctime: Timestamp,
     pub(crate) uid: u32,
     pub(crate) gid: u32,
     pub(crate) mode: mode_t,
+    pub(crate) nlink: u32,
+    // the origin of this directory was modified since it was last built
+    pub(crate) dirty: bool,
+    // this directory was built from the listings of all its sources at least once
+    pub(crate) built: bool,
+    // the index of the source that produced the entry, if not a directory leading to where
+    // some source is mounted
+    pub(crate) owner: Option<usize>,
+    // the name the source produced the entry as, should it be renamed to resolve a conflict
+    pub(crate) source_name: Option<OsString>,
     // perm: fuse3::perm_from_mode_and_kind(FileType::Directory, 0755),
 }
 
-pub struct Nodes(pub(crate) Arena<Node>);
+// The second field retains the generation of the removed nodes by their inode, such that the
+// node that reuses the inode will be given a newer generation.
+pub struct Nodes(pub(crate) Arena<Node>, HashMap<u64, u64>);
 
 impl Node {
     pub fn link(&mut self, name: OsString, entry: Entry) {
         let (mode, size) = match entry {
-            Entry::Dir(_) => (0o755, Some(0)),
+            Entry::Dir(_) | Entry::LazyDir(_) => (0o755, Some(0)),
             Entry::Filter(_) => (0o644, None),
             Entry::Filtrated(ref f) => (0o644, Some(f.len() as u64)),
             Entry::PreciseFilter(_) => (0o644, None),
+            Entry::StreamFilter(_) => (0o644, None),
+            Entry::Symlink(ref target) => (0o777, Some(target.as_os_str().len() as u64)),
+        };
+        let attr = entry.attr()
+            .cloned()
+            .unwrap_or_default();
+        // the size of an unchanged entry remains as it was resolved
+        let size = if self.unchanged(&entry) { self.size } else { size };
+        // Relinking a directory with a directory should retain the existing mapping of its
+        // children, as the entry provided by sources will not know about these.
+        // A lazy directory is kept as a directory that knows how to produce its children.
+        let entry = match entry {
+            Entry::LazyDir(lazy) => Entry::Dir(Dir {
+                attr: lazy.attr.clone(),
+                lazy: Some(lazy),
+                ..Default::default()
+            }),
+            entry => entry,
+        };
+        let entry = match (self.entry.take(), entry) {
+            (Some(Entry::Dir(dir)), Entry::Dir(new)) => Entry::Dir(Dir {
+                attr: new.attr,
+                lazy: new.lazy,
+                ..dir
+            }),
+            (_, entry) => entry,
         };
         self.name = name;
 
-        self.size = size.into();
-        self.time = SystemTime::now().into();
+        let now = SystemTime::now();
+        self.size = size;
+        self.atime = attr.atime.unwrap_or(now).into();
+        self.mtime = attr.mtime.unwrap_or(now).into();
+        self.ctime = attr.ctime.unwrap_or(now).into();
         self.generation += 1;
         self.entry = Some(entry);
-        self.mode = mode;
+        self.mode = attr.mode.unwrap_or(mode);
+        self.uid = attr.uid.unwrap_or_default();
+        self.gid = attr.gid.unwrap_or_default();
+        self.nlink = attr.nlink.unwrap_or_default();
     }
 
-    fn dir(&mut self) -> Option<&mut Dir> {
+    /// Whether the entry is the same as the one currently linked, such that relinking it may
+    /// be avoided.  Entries without a modification time are always considered to be changed.
+    pub(crate) fn unchanged(&self, entry: &Entry) -> bool {
+        let (current, new) = match (&self.entry, entry) {
+            (Some(Entry::Dir(Dir { lazy: None, attr, .. })), Entry::Dir(new)) => (attr, &new.attr),
+            (Some(Entry::Dir(Dir { lazy: Some(_), attr, .. })), Entry::LazyDir(new)) => (attr, &new.attr),
+            (Some(Entry::Filter(current)), Entry::Filter(new)) => (&current.attr, &new.attr),
+            (Some(Entry::PreciseFilter(current)), Entry::PreciseFilter(new)) => (&current.attr, &new.attr),
+            (Some(Entry::StreamFilter(current)), Entry::StreamFilter(new)) => (&current.attr, &new.attr),
+            (Some(Entry::Filtrated(current)), Entry::Filtrated(new)) => return current == new,
+            (Some(Entry::Symlink(current)), Entry::Symlink(new)) => return current == new,
+            _ => return false,
+        };
+        current.unmodified(new)
+    }
+
+    fn dir(&mut self) -> Option<&mut BTreeMap<OsString, u64>> {
         match &mut self.entry {
-            Some(Entry::Dir(dir)) => Some(dir),
+            Some(Entry::Dir(dir)) => Some(&mut dir.children),
             _ => None,
         }
     }
@@ -80,17 +150,75 @@ impl Default for Node {
             generation: 0,
 
             size: None,
-            time: Timestamp::new(0, 0),
+            atime: Timestamp::new(0, 0),
+            mtime: Timestamp::new(0, 0),
+            ctime: Timestamp::new(0, 0),
             gid: 0,
             uid: 0,
             mode: 0,
+            nlink: 0,
+            dirty: false,
+            built: false,
+            owner: None,
+            source_name: None,
         }
     }
 }
 
 impl Nodes {
     pub(crate) fn new_node(&mut self) -> NodeId {
-        self.0.new_node(Node::default())
+        let node_id = self.0.new_node(Node::default());
+        if let Some(generation) = self.1.remove(&(usize::from(node_id) as u64)) {
+            self[node_id].generation = generation;
+        }
+        node_id
+    }
+
+    /// Remove the child with the name from the node along with all its descendants, returning
+    /// the inodes that were removed.
+    pub(crate) fn remove_child(&mut self, node_id: NodeId, name: &OsStr) -> Vec<u64> {
+        let Ok(child_node_id) = self.basic_lookup_node_id_name(node_id, name) else {
+            return Vec::new();
+        };
+        if let Some(dir) = self[node_id].dir() {
+            dir.remove(name);
+        }
+        let removed = child_node_id.descendants(&self.0)
+            .map(|nid| (usize::from(nid) as u64, self[nid].generation))
+            .collect::<Vec<_>>();
+        child_node_id.remove_subtree(&mut self.0);
+        removed.into_iter()
+            .map(|(inode, generation)| {
+                self.1.insert(inode, generation);
+                inode
+            })
+            .collect()
+    }
+
+    /// Reconcile the children of the node with the listing that is about to be linked, such
+    /// that the children not in the listing, or those which will change between being a
+    /// directory and not, will be removed.  Returns the inodes that were removed.
+    pub(crate) fn reconcile<'a>(
+        &mut self,
+        node_id: NodeId,
+        listing: impl IntoIterator<Item = (&'a OsStr, &'a Entry)>,
+    ) -> Vec<u64> {
+        let listed = listing.into_iter()
+            .map(|(name, entry)| (name, entry.is_dir()))
+            .collect::<HashMap<_, _>>();
+        let stale = node_id.children(&self.0)
+            .filter_map(|nid| {
+                let node = &self[nid];
+                let is_dir = matches!(node.entry, Some(Entry::Dir(_)));
+                match listed.get(node.name.as_os_str()) {
+                    Some(listed_dir) if *listed_dir == is_dir => None,
+                    _ => Some(node.name.clone()),
+                }
+            })
+            .collect::<Vec<_>>();
+        stale.into_iter()
+            .flat_map(|name| self.remove_child(node_id, &name))
+            .collect()
     }
 
     pub(crate) fn link_entry(
@@ -98,7 +226,7 @@ impl Nodes {
         node_id: NodeId,
         name: OsString,
         entry: Entry,
-    ) -> Result<(), NodeLookupError> {
+    ) -> Result<NodeId, NodeLookupError> {
         let child_node_id = match self.basic_lookup_node_id_name(node_id, name.as_ref()) {
             Ok(node_id) => node_id,
             Err(NodeLookupError::NoSuchName(..)) => {
@@ -114,7 +242,7 @@ impl Nodes {
         };
 
         self[child_node_id].link(name, entry);
-        Ok(())
+        Ok(child_node_id)
     }
 
     pub(crate) fn path_of_inode(&self, inode: u64) -> Result<PathBuf, NoSuchNode> {
@@ -127,6 +255,61 @@ impl Nodes {
             .collect::<PathBuf>())
     }
 
+    /// Mark the directory at the path as dirty, returning the inode of the directory and the
+    /// inode of the child with the name, if one exists.
+    #[cfg(feature = "watch")]
+    pub(crate) fn mark_dirty(&mut self, path: &Path, name: Option<&OsStr>) -> Option<(u64, Option<u64>)> {
+        let node_id = self.path_to_node_id(path).ok()?;
+        let node = &mut self[node_id];
+        if !matches!(node.entry, Some(Entry::Dir(_))) {
+            return None;
+        }
+        node.dirty = true;
+        let child = name
+            .and_then(|name| self.basic_lookup_node_id_name(node_id, name).ok())
+            .map(|nid| usize::from(nid) as u64);
+        Some((usize::from(node_id) as u64, child))
+    }
+
+    /// The path of the directory as requested from the source at the index, where the path
+    /// consists of the nodes up to `depth` levels above the directory; should some node along
+    /// the path be owned by another source the request is not for this source, unless `shared`.
+    pub(crate) fn request_path(
+        &self,
+        node_id: NodeId,
+        depth: usize,
+        source: usize,
+        shared: bool,
+    ) -> Option<PathBuf> {
+        let nodes = node_id.ancestors(&self.0)
+            .take(depth)
+            .map(|nid| &self[nid])
+            .collect::<Vec<_>>();
+        let mut request = PathBuf::new();
+        for node in nodes.into_iter().rev() {
+            if !shared && node.owner.is_some_and(|owner| owner != source) {
+                return None;
+            }
+            request.push(node.source_name.as_ref().unwrap_or(&node.name));
+        }
+        Some(request)
+    }
+
+    pub(crate) fn path_to_node_id(&self, path: &Path) -> Result<NodeId, Error> {
+        let mut comps = path.components().peekable();
+        if comps.peek() == Some(&Component::RootDir) {
+            // discard the root component
+            comps.next();
+        }
+        let mut result = self.basic_node_id(1)?;
+        // XXX this assumes the incoming path is fully normalize, i.e. without
+        // Component::ParentDir in the mix
+        while let Some(Component::Normal(fragment)) = comps.next() {
+            result = self.basic_lookup_node_id_name(result, fragment)?;
+        }
+        Ok(result)
+    }
+
     pub(crate) fn basic_node_id(&self, inode: u64) -> Result<NodeId, NoSuchNode> {
         let arena = &self.0;
         let index = inode as usize;
@@ -134,7 +317,7 @@ impl Nodes {
             .get_node_id_at(
                 index.try_into().map_err(|_| NoSuchNode(inode))?
             )
-            .ok_or_else(|| NoSuchNode(inode))
+            .ok_or(NoSuchNode(inode))
     }
 
     pub(crate) fn basic_lookup_node_id_name(
@@ -150,7 +333,7 @@ impl Nodes {
                 Into::<usize>::into(node_id) as u64
             ))?
         {
-            Entry::Dir(dir) => Ok(dir),
+            Entry::Dir(dir) => Ok(&dir.children),
             _ => Err(NodeLookupError::NotDirEntry(
                 Into::<usize>::into(node_id) as u64
             )),
@@ -178,24 +361,24 @@ impl Default for Nodes {
         let mut arena = Arena::new();
         let root = arena.new_node(Node::default());
         let node = &mut arena[root].get_mut();
-        node.link(OsString::new(), Entry::Dir(BTreeMap::new()));
+        node.link(OsString::new(), Entry::Dir(Dir::default()));
 
         // `FUSE_ROOT_ID` is defined as 1
         let node_id: usize = root.into();
         assert!(node_id == 1);
-        Self(arena)
+        Self(arena, HashMap::new())
     }
 }
 
 impl Index<NodeId> for Nodes {
     type Output = Node;
     fn index(&self, node: NodeId) -> &Self::Output {
-        &self.0[node].get()
+        self.0[node].get()
     }
 }
 
 impl IndexMut<NodeId> for Nodes {
     fn index_mut(&mut self, node: NodeId) -> &mut Node {
-        (&mut self.0[node]).get_mut()
+        self.0[node].get_mut()
     }
 }
@@ -1,14 +1,10 @@
-use bytes::Bytes;
 use indextree::NodeId;
 use fuse3::{
     raw::prelude::*,
     Errno,
     Result,
 };
-use std::{
-    cmp::min,
-    ffi::OsStr,
-};
+use std::ffi::OsStr;
 
 use crate::{
     entry::Entry,
@@ -52,28 +48,35 @@ impl Nodes {
         handler: impl Fn((&'a Node, FileAttr)) -> Result<T>
     ) -> Result<T> {
         let arena = &self.0;
+        // the node may have been removed since it was looked up, should the lock on the nodes
+        // have been released in between
+        if node_id.is_removed(arena) {
+            return Err(Errno::from(libc::ENOENT));
+        }
         let node = &arena[node_id];
         let inner = node.get();
         let kind = match inner.entry
             .as_ref()
             .ok_or_else(|| Errno::from(libc::ENOENT))?
         {
-            Entry::Dir(_) => FileType::Directory,
+            Entry::Dir(_) | Entry::LazyDir(_) => FileType::Directory,
             Entry::Filter(_) => FileType::RegularFile,
             Entry::Filtrated(_) => FileType::RegularFile,
             Entry::PreciseFilter(_) => FileType::RegularFile,
+            Entry::StreamFilter(_) => FileType::RegularFile,
+            Entry::Symlink(_) => FileType::Symlink,
         };
         handler((inner, FileAttr {
             ino: Into::<usize>::into(node_id) as u64,  // FIXME change to usize::from when possible
             size: inner.size
                 .unwrap_or(0),
             blocks: 0,
-            atime: inner.time,
-            mtime: inner.time,
-            ctime: inner.time,
-            kind: kind,
+            atime: inner.atime,
+            mtime: inner.mtime,
+            ctime: inner.ctime,
+            kind,
             perm: fuse3::perm_from_mode_and_kind(kind, inner.mode),
-            nlink: 0,
+            nlink: inner.nlink,
             uid: inner.uid,
             gid: inner.gid,
             rdev: 0,
@@ -81,41 +84,7 @@ impl Nodes {
         }))
     }
 
-    pub(crate) fn attr_for_inode(&self, inode: u64) -> Result<(&Node, FileAttr)> {
-        self.attr_for_node_id(
-            self.node_id(inode)?
-        )
-    }
-
     pub(crate) fn attr_for_node_id(&self, node_id: NodeId) -> Result<(&Node, FileAttr)> {
         self.with_node_id(node_id, Result::Ok)
     }
-
-    pub(crate) async fn read(
-        &self,
-        node_id: NodeId,
-        offset: u64,
-        size: u32,
-    ) -> Result<Bytes> {
-        let arena = &self.0;
-        let node = &arena[node_id];
-        let inner = node.get();
-        match inner.entry
-            .as_ref()
-            .ok_or_else(|| Errno::from(libc::ENOENT))?
-        {
-            Entry::Dir(_) => Err(Errno::from(libc::ENOTDIR)),
-            Entry::Filter(f) => {
-                let r = f.filtrate()
-                    .await
-                    .map_err(|_| Errno::from(libc::EIO))?;
-                Ok(r.slice(offset as usize..min(r.len(), (size as u64 + offset) as usize)))
-            }
-            Entry::Filtrated(r) => Ok(r.slice(offset as usize..min(r.len(), (size as u64 + offset) as usize))),
-            Entry::PreciseFilter(f) => Ok(f.filtrate(offset, size)
-                .await
-                .map_err(|_| Errno::from(libc::EIO))?),
-        }
-    }
-
 }
@@ -1,3 +1,4 @@
+use futures_util::future::BoxFuture;
 use std::{
     ffi::OsString,
     path::{
@@ -10,8 +11,8 @@ use crate::{
     entry::Entry,
     error::SourceError,
     traits::{
-        Effect,
-        EffsSource,
+        AsyncEffect,
+        AsyncEffsSource,
     },
 };
 
@@ -22,6 +23,7 @@ pub struct Source<S> {
     dest_path: PathBuf,
     // Additional struct providing the data required for the filter setup.
     setup: S,
+    label: Option<String>,
     // TODO cache goes here?
 }
 
@@ -31,19 +33,47 @@ impl<S> Source<S> {
         dest_path: PathBuf,
         setup: S,
     ) -> Self {
+        // the destination is always relative to the mount point
+        let dest_path = match dest_path.strip_prefix("/") {
+            Ok(dest_path) => dest_path.to_path_buf(),
+            Err(_) => dest_path,
+        };
         Self {
             source_path,
             dest_path,
             setup,
+            label: None,
         }
     }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
 }
 
-impl<E> EffsSource for Source<E>
+impl<E> AsyncEffsSource for Source<E>
 where
-    E: Effect
+    E: AsyncEffect
 {
-    fn dir(&mut self, request: &Path) -> Result<Vec<(OsString, Entry)>, SourceError> {
-        Ok(self.setup.apply(self.source_path.as_path(), request)?)
+    fn dir<'a>(
+        &'a mut self,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, SourceError>> {
+        Box::pin(async move {
+            Ok(self.setup.apply(self.source_path.as_path(), request).await?)
+        })
+    }
+
+    fn dest_path(&self) -> &Path {
+        self.dest_path.as_path()
+    }
+
+    fn origin(&self) -> Option<&Path> {
+        Some(self.source_path.as_path())
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
     }
 }
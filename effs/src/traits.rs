@@ -1,3 +1,7 @@
+use futures_util::future::{
+    self,
+    BoxFuture,
+};
 use std::{
     ffi::OsString,
     path::Path,
@@ -37,6 +41,93 @@ pub trait Effect<Error=EffectError>: Send + Sync + 'static {
 /// or filtrated an error will happen.
 ///
 /// Returns a result with a vector containing a listing of `OsString` pointing to an `Entry`.
+///
+/// `dest_path` is the location relative to the mount point where the listings produced by
+/// this source will be grafted onto; only requests at or below that location will be passed
+/// to `dir`, with the `dest_path` prefix stripped.
+///
+/// `origin` is the location on the system the listings are derived from, if any, such that
+/// changes to it (or to what is at `request` below it) may be watched for.
+///
+/// `label` identifies the source, such as when names produced by multiple sources conflict;
+/// sources without a label are identified by the order they were pushed onto `Effs`.
 pub trait EffsSource<Error=SourceError>: Send + Sync + 'static {
     fn dir(&mut self, request: &Path) -> Result<Vec<(OsString, Entry)>, Error>;
+
+    fn dest_path(&self) -> &Path {
+        Path::new("")
+    }
+
+    fn origin(&self) -> Option<&Path> {
+        None
+    }
+
+    fn label(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The asynchronous version of `Effect`.
+///
+/// Effects that need to perform I/O or otherwise take a while to produce their listing should
+/// implement this rather than `Effect`, so that other requests to the filesystem may proceed
+/// while the listing is being produced.  All `Effect`s are also `AsyncEffect`s.
+pub trait AsyncEffect<Error=EffectError>: Send + Sync + 'static {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, Error>>;
+}
+
+impl<T: Effect> AsyncEffect for T {
+    fn apply<'a>(
+        &'a mut self,
+        origin: &'a Path,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, EffectError>> {
+        Box::pin(future::ready(Effect::apply(self, origin, request)))
+    }
+}
+
+/// The asynchronous version of `EffsSource`, which is what `Effs` makes use of.  All
+/// `EffsSource`s are also `AsyncEffsSource`s.
+pub trait AsyncEffsSource<Error=SourceError>: Send + Sync + 'static {
+    fn dir<'a>(
+        &'a mut self,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, Error>>;
+
+    fn dest_path(&self) -> &Path {
+        Path::new("")
+    }
+
+    fn origin(&self) -> Option<&Path> {
+        None
+    }
+
+    fn label(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl<T: EffsSource> AsyncEffsSource for T {
+    fn dir<'a>(
+        &'a mut self,
+        request: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<(OsString, Entry)>, SourceError>> {
+        Box::pin(future::ready(EffsSource::dir(self, request)))
+    }
+
+    fn dest_path(&self) -> &Path {
+        EffsSource::dest_path(self)
+    }
+
+    fn origin(&self) -> Option<&Path> {
+        EffsSource::origin(self)
+    }
+
+    fn label(&self) -> Option<&str> {
+        EffsSource::label(self)
+    }
 }
@@ -0,0 +1,227 @@
+use fuse3::notify::Notify;
+use futures_util::StreamExt;
+use inotify::{
+    EventMask,
+    EventStream,
+    Inotify,
+    WatchDescriptor,
+    WatchMask,
+    Watches,
+};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use tokio::{
+    sync::RwLock,
+    task::JoinHandle,
+};
+
+use crate::node::Nodes;
+
+const MASK: WatchMask = WatchMask::ATTRIB
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::CREATE)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::DELETE_SELF)
+    .union(WatchMask::MODIFY)
+    .union(WatchMask::MOVE_SELF)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO);
+
+/// Watches the origins of the sources with inotify, marking the directories built from them
+/// as dirty when they are modified such that they will be rebuilt when next accessed.
+///
+/// The kernel is also told to drop what it has cached for these directories and their entries,
+/// though this is only possible once a `Notify` has been provided through `Filesystem::poll`,
+/// as that is the only place where fuse3 makes one available, which is rarely called as only
+/// some programs will poll the files; until then the kernel will only notice the changes once
+/// the cached entries and attributes expire, so their TTLs are much shorter while watching.
+pub(crate) struct Watcher {
+    state: Arc<Mutex<WatcherState>>,
+    nodes: Arc<RwLock<Nodes>>,
+    notify: Arc<Mutex<Option<Notify>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct WatcherState {
+    // only available until the events start being consumed
+    inotify: Option<Inotify>,
+    watches: Watches,
+    // maps from the watched origins to the paths of the directories built from them
+    paths: HashMap<WatchDescriptor, Vec<PathBuf>>,
+}
+
+impl Watcher {
+    pub(crate) fn new(
+        nodes: Arc<RwLock<Nodes>>,
+        notify: Arc<Mutex<Option<Notify>>>,
+    ) -> io::Result<Self> {
+        let inotify = Inotify::init()?;
+        let watches = inotify.watches();
+        Ok(Self {
+            state: Arc::new(Mutex::new(WatcherState {
+                inotify: Some(inotify),
+                watches,
+                paths: HashMap::new(),
+            })),
+            nodes,
+            notify,
+            task: Mutex::new(None),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, WatcherState> {
+        self.state
+            .lock()
+            .expect("watcher lock poisoned")
+    }
+
+    /// Watch the origin for changes, which will mark the directory at `path` as dirty.
+    ///
+    /// This must be called from within a tokio runtime, as the events will be consumed by a
+    /// task that is spawned on the first call.
+    pub(crate) fn watch(&self, origin: &Path, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let wd = state.watches.add(origin, MASK)?;
+        let paths = state.paths
+            .entry(wd)
+            .or_default();
+        if !paths.iter().any(|p| p == path) {
+            paths.push(path.to_path_buf());
+        }
+
+        if let Some(inotify) = state.inotify.take() {
+            let events = inotify.into_event_stream(vec![0; 4096])?;
+            let task = tokio::spawn(run(
+                events,
+                self.state.clone(),
+                self.nodes.clone(),
+                self.notify.clone(),
+            ));
+            *self.task
+                .lock()
+                .expect("watcher lock poisoned") = Some(task);
+        }
+        Ok(())
+    }
+
+    /// Stop watching the origins for the directories that were removed, as decided by the
+    /// predicate on their paths.
+    pub(crate) fn unwatch(&self, removed: impl Fn(&Path) -> bool) {
+        let mut state = self.state();
+        let WatcherState { watches, paths, .. } = &mut *state;
+        paths.retain(|wd, paths| {
+            paths.retain(|path| !removed(path));
+            if !paths.is_empty() {
+                return true;
+            }
+            if let Err(e) = watches.remove(wd.clone()) {
+                tracing::debug!("failed to stop watching: {e}");
+            }
+            false
+        });
+    }
+
+    /// The paths of the directories with watched origins.
+    #[cfg(test)]
+    pub(crate) fn watched(&self) -> Vec<PathBuf> {
+        let mut watched = self.state()
+            .paths
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        watched.sort();
+        watched
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().ok().and_then(Option::take) {
+            task.abort();
+        }
+    }
+}
+
+async fn run(
+    mut events: EventStream<Vec<u8>>,
+    state: Arc<Mutex<WatcherState>>,
+    nodes: Arc<RwLock<Nodes>>,
+    notify: Arc<Mutex<Option<Notify>>>,
+) {
+    while let Some(event) = events.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("failed to read inotify events: {e}");
+                break;
+            }
+        };
+        let paths = {
+            let mut state = state
+                .lock()
+                .expect("watcher lock poisoned");
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                // events were lost, so everything may have been modified
+                state.paths
+                    .values()
+                    .flatten()
+                    .cloned()
+                    .collect()
+            } else if event.mask.contains(EventMask::IGNORED) {
+                // the origin is gone, and the next build of its directories will find out
+                state.paths
+                    .remove(&event.wd)
+                    .unwrap_or_default()
+            } else {
+                state.paths
+                    .get(&event.wd)
+                    .cloned()
+                    .unwrap_or_default()
+            }
+        };
+        for path in paths {
+            tracing::debug!("origin of {path:?} modified: {:?} {:?}", event.mask, event.name);
+            let marked = nodes
+                .write()
+                .await
+                .mark_dirty(&path, event.name.as_deref());
+            let Some((inode, child)) = marked else {
+                continue;
+            };
+            let notify = notify
+                .lock()
+                .expect("notify lock poisoned")
+                .clone();
+            if let Some(notify) = notify {
+                invalidate(notify, inode, event.name.clone(), child).await;
+            }
+        }
+    }
+}
+
+async fn invalidate(notify: Notify, inode: u64, name: Option<OsString>, child: Option<u64>) {
+    notify.clone()
+        .invalid_inode(inode, 0, 0)
+        .await;
+    if let Some(name) = name {
+        notify.clone()
+            .invalid_entry(inode, name)
+            .await;
+    }
+    if let Some(child) = child {
+        notify.invalid_inode(child, 0, 0)
+            .await;
+    }
+}
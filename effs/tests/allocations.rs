@@ -0,0 +1,97 @@
+//! The allocations made while serving requests, which are counted by a global allocator that
+//! is only installed for this test binary.
+
+use bytes::Bytes;
+use effs::{
+    Effs,
+    Refresh,
+    entry::Entry,
+    error::EffectError,
+    source::Source,
+    traits::Effect,
+};
+use fuse3::raw::{
+    Filesystem,
+    Request,
+};
+use futures_util::StreamExt;
+use std::{
+    alloc::{
+        GlobalAlloc,
+        Layout,
+        System,
+    },
+    cell::Cell,
+    ffi::OsString,
+    path::Path,
+};
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Tracks the bytes allocated by each thread, so the tests running concurrently on other
+/// threads will not be counted.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size())).ok();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Provides the number of files in the listing.
+struct Many(usize);
+
+impl Effect for Many {
+    fn apply(&mut self, _: &Path, _: &Path) -> Result<Vec<(OsString, Entry)>, EffectError> {
+        Ok((0..self.0)
+            .map(|i| (format!("{i:08}").into(), Bytes::from_static(b"").into()))
+            .collect())
+    }
+}
+
+#[tokio::test]
+async fn opendir_readdirplus() -> anyhow::Result<()> {
+    async fn allocated_by_listing(len: usize) -> anyhow::Result<usize> {
+        let effs = Effs::default();
+        let source = Source::new("".into(), "".into(), Many(len));
+        effs.push_source_with_refresh(source, Refresh::Once).await?;
+        // the directory is built when it is first opened
+        let dir = effs.opendir(Request::default(), 1, 0).await?;
+        effs.releasedir(Request::default(), 1, dir.fh, 0).await?;
+
+        let before = ALLOCATED.with(Cell::get);
+        let dir = effs.opendir(Request::default(), 1, 0).await?;
+        let entries = effs.readdirplus(Request::default(), 1, dir.fh, 2, 0).await?
+            .entries
+            .take(16)
+            .collect::<Vec<_>>()
+            .await;
+        let allocated = ALLOCATED.with(Cell::get) - before;
+        assert_eq!(entries.len(), 16);
+        assert_eq!(entries[0].as_ref().map(|entry| entry.name.clone()).ok(), Some("00000000".into()));
+        effs.releasedir(Request::default(), 1, dir.fh, 0).await?;
+        Ok(allocated)
+    }
+
+    let (small, large) = (1_000, 100_000);
+    let small_allocated = allocated_by_listing(small).await?;
+    let large_allocated = allocated_by_listing(large).await?;
+    // The snapshot taken by opendir has to refer to every entry, but nothing more than that is
+    // allowed for every additional entry, such as their names or their attributes.
+    let per_entry = large_allocated.saturating_sub(small_allocated) / (large - small);
+    assert!(
+        per_entry <= 48,
+        "{large_allocated} bytes allocated for the large listing, {small_allocated} for the small",
+    );
+    Ok(())
+}